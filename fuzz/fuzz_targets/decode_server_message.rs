@@ -0,0 +1,41 @@
+#![no_main]
+#![allow(dead_code)]
+
+// only the wire modules, pulled in the same way the bins declare them
+#[path = "../../src/bitpack.rs"]
+mod bitpack;
+#[path = "../../src/client_to_server.rs"]
+mod client_to_server;
+#[path = "../../src/codec.rs"]
+mod codec;
+#[path = "../../src/components.rs"]
+mod components;
+#[path = "../../src/game_objects.rs"]
+mod game_objects;
+#[path = "../../src/input.rs"]
+mod input;
+#[path = "../../src/replication.rs"]
+mod replication;
+#[path = "../../src/rpc.rs"]
+mod rpc;
+#[path = "../../src/server_to_client.rs"]
+mod server_to_client;
+#[path = "../../src/settings.rs"]
+mod settings;
+#[path = "../../src/snapshot.rs"]
+mod snapshot;
+#[path = "../../src/wire_enum.rs"]
+mod wire_enum;
+
+use libfuzzer_sys::fuzz_target;
+
+use server_to_client::ServerToClientMessage;
+
+// the exact decode the client rx loop runs on every datagram
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = codec::decode::<ServerToClientMessage>(data) {
+        let bytes = codec::encode(&message).expect("decoded message failed to encode");
+        codec::decode::<ServerToClientMessage>(&bytes)
+            .expect("re-encoded message failed to decode");
+    }
+});
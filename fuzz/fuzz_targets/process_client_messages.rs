@@ -0,0 +1,130 @@
+#![no_main]
+#![allow(dead_code)]
+
+// everything the server game loop touches, and nothing that needs a window
+#[path = "../../src/bitpack.rs"]
+mod bitpack;
+#[path = "../../src/bookkeeping.rs"]
+mod bookkeeping;
+#[path = "../../src/client_to_server.rs"]
+mod client_to_server;
+#[path = "../../src/codec.rs"]
+mod codec;
+#[path = "../../src/components.rs"]
+mod components;
+#[path = "../../src/enque_outbound_messages.rs"]
+mod enque_outbound_messages;
+#[path = "../../src/game_objects.rs"]
+mod game_objects;
+#[path = "../../src/input.rs"]
+mod input;
+#[path = "../../src/lag_compensation.rs"]
+mod lag_compensation;
+#[path = "../../src/network_stats.rs"]
+mod network_stats;
+#[path = "../../src/rate_limiting.rs"]
+mod rate_limiting;
+#[path = "../../src/replication.rs"]
+mod replication;
+#[path = "../../src/rpc.rs"]
+mod rpc;
+#[path = "../../src/server_game.rs"]
+mod server_game;
+#[path = "../../src/server_state.rs"]
+mod server_state;
+#[path = "../../src/server_stats.rs"]
+mod server_stats;
+#[path = "../../src/server_to_client.rs"]
+mod server_to_client;
+#[path = "../../src/server_udp_networking.rs"]
+mod server_udp_networking;
+#[path = "../../src/settings.rs"]
+mod settings;
+#[path = "../../src/shutdown.rs"]
+mod shutdown;
+#[path = "../../src/snapshot.rs"]
+mod snapshot;
+#[path = "../../src/systems.rs"]
+mod systems;
+#[path = "../../src/tick_scheduler.rs"]
+mod tick_scheduler;
+#[path = "../../src/traffic_capture.rs"]
+mod traffic_capture;
+#[path = "../../src/validation.rs"]
+mod validation;
+#[path = "../../src/wire_enum.rs"]
+mod wire_enum;
+
+use std::net::SocketAddr;
+
+use lazy_static::lazy_static;
+use libfuzzer_sys::fuzz_target;
+
+use bookkeeping::{add_client, CLIENT_OUTBOUND_MAILBOXES};
+use client_to_server::{ClientToServerMessage, ClientToServerMessageBundle};
+use server_state::ServerState;
+use server_udp_networking::INCOMING_MESSAGE_QUEUE;
+
+const NUM_FAKE_CLIENTS: u16 = 4;
+
+lazy_static! {
+    static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    static ref FAKE_CLIENT_IDS: Vec<u32> = RUNTIME.block_on(register_fake_clients());
+}
+
+/// Clients exist only in the bookkeeping maps, no sockets are involved.
+async fn register_fake_clients() -> Vec<u32> {
+    let mut client_ids = Vec::new();
+    for i in 0..NUM_FAKE_CLIENTS {
+        let socket_address = SocketAddr::from(([127, 0, 0, 1], 40000 + i));
+        client_ids.push(add_client(socket_address, 0).await);
+    }
+    client_ids
+}
+
+/// Input is a run of frames: one byte of client index, a little endian u16 length,
+/// then that many bytes of packet. A truncated frame ends the run.
+fn frames(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        if rest.len() < 3 {
+            return None;
+        }
+        let client_index = rest[0];
+        let len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+        let packet = rest.get(3..3 + len)?;
+        rest = &rest[3 + len..];
+        Some((client_index, packet))
+    })
+}
+
+async fn drain_mailboxes() {
+    let clients_read = CLIENT_OUTBOUND_MAILBOXES.read().await;
+    for queue in clients_read.values() {
+        while queue.pop().is_some() {}
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let client_ids = &*FAKE_CLIENT_IDS;
+    RUNTIME.block_on(async {
+        let mut state = ServerState::new();
+        for (client_index, packet) in frames(data) {
+            let client_id = client_ids[client_index as usize % client_ids.len()];
+            if let Ok(message) = codec::decode::<ClientToServerMessage>(packet) {
+                let _ =
+                    INCOMING_MESSAGE_QUEUE.push(ClientToServerMessageBundle { client_id, message });
+            }
+
+            // same order as the server main loop: drain the queue, step, then send
+            // what the step queued
+            server_game::process_message_queue(&mut state).await;
+            server_game::step(&mut state);
+            server_game::send_outbox(&mut state).await;
+            drain_mailboxes().await;
+        }
+    });
+});
@@ -0,0 +1,308 @@
+use std::{fmt, marker::PhantomData};
+
+use glam::Vec2;
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::codec::MAX_PACKET_SIZE;
+
+// nothing packed can be bigger than the packet it rides in
+const MAX_PACKED_BYTES: usize = MAX_PACKET_SIZE as usize;
+// varints go out in chunks of this many bits, each followed by a continue bit
+const VARINT_CHUNK_BITS: u32 = 4;
+// a varint is never shorter than its first chunk
+pub const MIN_VARINT_BITS: usize = VARINT_CHUNK_BITS as usize + 1;
+
+////////////////////////    BITS    ////////////////////////
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bits_used: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            bits_used: 0,
+        }
+    }
+
+    /// Writes the low `bits` bits of `value`, least significant first.
+    pub fn write(&mut self, value: u64, bits: u32) {
+        for i in 0..bits {
+            if self.bits_used.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (self.bits_used % 8);
+            self.bits_used += 1;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write(value as u64, 1);
+    }
+
+    /// Small values take few bits: under 16 is 5 bits, under 256 is 10.
+    pub fn write_varint(&mut self, mut value: u32) {
+        loop {
+            self.write(value as u64, VARINT_CHUNK_BITS);
+            value >>= VARINT_CHUNK_BITS;
+            self.write_bool(value != 0);
+            if value == 0 {
+                return;
+            }
+        }
+    }
+
+    pub fn bits_used(&self) -> usize {
+        self.bits_used
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Default for BitWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    bits_read: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            bits_read: 0,
+        }
+    }
+
+    pub fn read(&mut self, bits: u32) -> Option<u64> {
+        if bits as usize > self.bits_left() {
+            return None;
+        }
+        let mut value = 0;
+        for i in 0..bits {
+            let byte = self.bytes[self.bits_read / 8];
+            let bit = (byte >> (self.bits_read % 8)) & 1;
+            value |= (bit as u64) << i;
+            self.bits_read += 1;
+        }
+        Some(value)
+    }
+
+    pub fn read_bool(&mut self) -> Option<bool> {
+        Some(self.read(1)? == 1)
+    }
+
+    pub fn read_varint(&mut self) -> Option<u32> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            value |= self.read(VARINT_CHUNK_BITS)? << shift;
+            shift += VARINT_CHUNK_BITS;
+            if !self.read_bool()? {
+                break;
+            }
+            if shift >= 32 {
+                return None;
+            }
+        }
+        u32::try_from(value).ok()
+    }
+
+    pub fn bits_left(&self) -> usize {
+        self.bytes.len() * 8 - self.bits_read
+    }
+
+    /// True once only the zero padding of the last byte is left.
+    pub fn is_finished(&self) -> bool {
+        let padding = self.bits_left();
+        padding < 8 && (padding == 0 || self.bytes[self.bits_read / 8] >> (self.bits_read % 8) == 0)
+    }
+}
+
+////////////////////////    QUANTIZATION    ////////////////////////
+/// Maps a float in `min..=max` onto whole steps of `precision`. Values outside the
+/// range are clamped to it, and the round trip error inside it is at most half a step.
+#[derive(Debug, Clone, Copy)]
+pub struct Quantization {
+    pub min: f32,
+    pub max: f32,
+    pub precision: f32,
+}
+
+impl Quantization {
+    pub const fn new(min: f32, max: f32, precision: f32) -> Self {
+        Self {
+            min,
+            max,
+            precision,
+        }
+    }
+
+    fn steps(&self) -> u32 {
+        ((self.max - self.min) / self.precision).ceil() as u32
+    }
+
+    pub fn bits(&self) -> u32 {
+        32 - self.steps().leading_zeros()
+    }
+
+    pub fn quantize(&self, value: f32) -> u32 {
+        if value.is_nan() {
+            return 0;
+        }
+        let step = ((value.clamp(self.min, self.max) - self.min) / self.precision).round() as u32;
+        step.min(self.steps())
+    }
+
+    pub fn dequantize(&self, step: u32) -> f32 {
+        (self.min + step as f32 * self.precision).min(self.max)
+    }
+
+    pub fn write(&self, writer: &mut BitWriter, value: f32) {
+        writer.write(self.quantize(value) as u64, self.bits());
+    }
+
+    pub fn read(&self, reader: &mut BitReader) -> Option<f32> {
+        let step = reader.read(self.bits())? as u32;
+        if step > self.steps() {
+            return None;
+        }
+        Some(self.dequantize(step))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vec2Quantization {
+    pub x: Quantization,
+    pub y: Quantization,
+}
+
+impl Vec2Quantization {
+    pub fn write(&self, writer: &mut BitWriter, value: Vec2) {
+        self.x.write(writer, value.x);
+        self.y.write(writer, value.y);
+    }
+
+    pub fn read(&self, reader: &mut BitReader) -> Option<Vec2> {
+        Some(Vec2::new(self.x.read(reader)?, self.y.read(reader)?))
+    }
+}
+
+////////////////////////    PACKING    ////////////////////////
+/// A type with a bit level layout. `unpack` returns None on anything it cannot read,
+/// including running out of bits.
+pub trait BitPack: Sized {
+    fn pack(&self, writer: &mut BitWriter);
+    fn unpack(reader: &mut BitReader) -> Option<Self>;
+}
+
+impl BitPack for u32 {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(*self);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        reader.read_varint()
+    }
+}
+
+impl<T: BitPack> BitPack for Vec<T> {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.len() as u32);
+        for item in self {
+            item.pack(writer);
+        }
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        let len = reader.read_varint()? as usize;
+        // every item takes at least a bit, so a forged length cannot allocate much
+        if len > reader.bits_left() {
+            return None;
+        }
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+            items.push(T::unpack(reader)?);
+        }
+        Some(items)
+    }
+}
+
+/// Puts `T` on the wire in its bit packed form, as a u16 byte count then the bytes,
+/// so it can sit inside an ordinary bincode message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packed<T>(pub T);
+
+impl<T: BitPack> Serialize for Packed<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut writer = BitWriter::new();
+        self.0.pack(&mut writer);
+        let bytes = writer.finish();
+        if bytes.len() > MAX_PACKED_BYTES {
+            return Err(serde::ser::Error::custom("packed data too long"));
+        }
+
+        // a tuple has no length prefix of its own in bincode, so this is the only one
+        let mut tuple = serializer.serialize_tuple(1 + bytes.len())?;
+        tuple.serialize_element(&(bytes.len() as u16))?;
+        for byte in &bytes {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de, T: BitPack> Deserialize<'de> for Packed<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PackedVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: BitPack> Visitor<'de> for PackedVisitor<T> {
+            type Value = Packed<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a byte count followed by that many bytes of packed data")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let len: u16 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let len = len as usize;
+                if len > MAX_PACKED_BYTES {
+                    return Err(de::Error::custom("packed data too long"));
+                }
+                let mut bytes = Vec::with_capacity(len);
+                for i in 0..len {
+                    let byte: u8 = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i + 1, &self))?;
+                    bytes.push(byte);
+                }
+
+                let mut reader = BitReader::new(&bytes);
+                let value =
+                    T::unpack(&mut reader).ok_or_else(|| de::Error::custom("bad packed data"))?;
+                if !reader.is_finished() {
+                    return Err(de::Error::custom("trailing packed data"));
+                }
+                Ok(Packed(value))
+            }
+        }
+
+        // bincode reads only as many elements as the visitor asks for, so the length
+        // here is just an upper bound
+        deserializer.deserialize_tuple(1 + MAX_PACKED_BYTES, PackedVisitor(PhantomData))
+    }
+}
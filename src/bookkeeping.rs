@@ -1,6 +1,6 @@
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
     sync::{
         atomic::{AtomicBool, AtomicU32},
         Arc,
@@ -12,6 +12,7 @@ use lazy_static::lazy_static;
 use tokio::sync::RwLock;
 
 use crate::{
+    network_stats::{ConnectionStats, ConnectionStatsSnapshot},
     server_udp_networking::{CLIENT_DISCONNECTED, INCOMING_MESSAGE_QUEUE},
     {
         client_to_server::{ClientToServerMessage, ClientToServerMessageBundle},
@@ -27,8 +28,16 @@ lazy_static! {
         Arc::new(RwLock::new(HashMap::new()));
     pub static ref SOCKET_ADDRESS_TO_CLIENT_ID: Arc<RwLock<HashMap<SocketAddr, u32>>> =
         Arc::new(RwLock::new(HashMap::new()));
+    pub static ref CLIENT_ID_TO_SOCKET_INDEX: Arc<RwLock<HashMap<u32, usize>>> =
+        Arc::new(RwLock::new(HashMap::new()));
     pub static ref CLIENT_OUTBOUND_MAILBOXES: RwLock<HashMap<u32, ClientMessageQueue>> =
         RwLock::new(HashMap::new());
+    pub static ref CLIENT_NETWORK_STATS: RwLock<HashMap<u32, Arc<ConnectionStats>>> =
+        RwLock::new(HashMap::new());
+    // clients that have been sent the world and now get game broadcasts. a std lock,
+    // since the game marks joins from inside synchronous request handlers
+    pub static ref JOINED_CLIENTS: std::sync::RwLock<HashSet<u32>> =
+        std::sync::RwLock::new(HashSet::new());
 }
 
 ////////////////////////    CLIENT BOOKKEEPING    ////////////////////////
@@ -36,7 +45,21 @@ pub fn get_next_connection_id() -> u32 {
     NEXT_CONNECTION_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
 }
 
-pub async fn add_client(socket_address: SocketAddr) -> u32 {
+/// Folds IPv4-mapped IPv6 addresses down to plain IPv4, so a host has one identity
+/// no matter which socket family it reached us through.
+pub fn canonical_socket_address(socket_address: SocketAddr) -> SocketAddr {
+    match socket_address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => socket_address,
+        },
+        SocketAddr::V4(_) => socket_address,
+    }
+}
+
+/// `socket_address` is the peer address as seen by the socket at `socket_index`,
+/// which is where replies to this client will be sent from.
+pub async fn add_client(socket_address: SocketAddr, socket_index: usize) -> u32 {
     let id = get_next_connection_id();
 
     let mailbox = Arc::new(ArrayQueue::new(100));
@@ -47,6 +70,12 @@ pub async fn add_client(socket_address: SocketAddr) -> u32 {
         clients_write.insert(id, mailbox);
     }
 
+    // Insert into CLIENT_NETWORK_STATS
+    {
+        let mut client_network_stats_write = CLIENT_NETWORK_STATS.write().await;
+        client_network_stats_write.insert(id, Arc::new(ConnectionStats::new()));
+    }
+
     // Insert into CLIENT_DISCONNECTED flag map
     {
         let disconnected = Arc::new(AtomicBool::new(false));
@@ -63,7 +92,13 @@ pub async fn add_client(socket_address: SocketAddr) -> u32 {
     // Insert into SOCKET_ADDRESS_TO_CLIENT_ID
     {
         let mut socket_address_to_client_id_write = SOCKET_ADDRESS_TO_CLIENT_ID.write().await;
-        socket_address_to_client_id_write.insert(socket_address, id);
+        socket_address_to_client_id_write.insert(canonical_socket_address(socket_address), id);
+    }
+
+    // Insert into CLIENT_ID_TO_SOCKET_INDEX
+    {
+        let mut client_id_to_socket_index_write = CLIENT_ID_TO_SOCKET_INDEX.write().await;
+        client_id_to_socket_index_write.insert(id, socket_index);
     }
 
     // announce that theres a new connection
@@ -98,14 +133,34 @@ pub async fn add_client(socket_address: SocketAddr) -> u32 {
     id
 }
 
+pub fn mark_joined(id: u32) {
+    JOINED_CLIENTS.write().unwrap().insert(id);
+}
+
+pub fn has_joined(id: u32) -> bool {
+    JOINED_CLIENTS.read().unwrap().contains(&id)
+}
+
+pub fn joined_clients() -> Vec<u32> {
+    JOINED_CLIENTS.read().unwrap().iter().copied().collect()
+}
+
 ///  Removes client allocated bookkeeping resources.
 pub async fn remove_client(id: u32) {
+    JOINED_CLIENTS.write().unwrap().remove(&id);
+
     // Remove from CLIENT_OUTBOUND_MAILBOXES
     {
         let mut clients_write = CLIENT_OUTBOUND_MAILBOXES.write().await;
         clients_write.remove(&id);
     }
 
+    // Remove from CLIENT_NETWORK_STATS
+    {
+        let mut client_network_stats_write = CLIENT_NETWORK_STATS.write().await;
+        client_network_stats_write.remove(&id);
+    }
+
     // Remove from CLIENT_DISCONNECTED flag map
     {
         let mut client_status_write = CLIENT_DISCONNECTED.write().await;
@@ -120,7 +175,8 @@ pub async fn remove_client(id: u32) {
             {
                 let mut socket_address_to_client_id_write =
                     SOCKET_ADDRESS_TO_CLIENT_ID.write().await;
-                socket_address_to_client_id_write.remove(socket_address);
+                socket_address_to_client_id_write
+                    .remove(&canonical_socket_address(*socket_address));
             }
         } else {
             eprintln!("Failed to find socket address for client {}", id);
@@ -128,6 +184,12 @@ pub async fn remove_client(id: u32) {
         }
     }
 
+    // Remove from CLIENT_ID_TO_SOCKET_INDEX
+    {
+        let mut client_id_to_socket_index_write = CLIENT_ID_TO_SOCKET_INDEX.write().await;
+        client_id_to_socket_index_write.remove(&id);
+    }
+
     // Remove from CLIENT_ID_TO_SOCKET_ADDRESS
     {
         let mut client_socket_addresses_write = CLIENT_ID_TO_SOCKET_ADDRESS.write().await;
@@ -136,3 +198,57 @@ pub async fn remove_client(id: u32) {
 
     println!("Client {} network resources cleaned up.", id);
 }
+
+////////////////////////    CLIENT NETWORK STATS    ////////////////////////
+pub async fn client_network_stats(client_id: u32) -> Option<Arc<ConnectionStats>> {
+    CLIENT_NETWORK_STATS.read().await.get(&client_id).cloned()
+}
+
+pub async fn client_network_stats_snapshot(client_id: u32) -> Option<ConnectionStatsSnapshot> {
+    let stats = client_network_stats(client_id).await?;
+    let mailbox_depth = match CLIENT_OUTBOUND_MAILBOXES.read().await.get(&client_id) {
+        Some(queue) => queue.len(),
+        None => 0,
+    };
+    Some(stats.snapshot(mailbox_depth))
+}
+
+pub async fn all_client_network_stats_snapshots() -> Vec<(u32, ConnectionStatsSnapshot)> {
+    let client_ids: Vec<u32> = CLIENT_NETWORK_STATS.read().await.keys().copied().collect();
+    let mut snapshots = Vec::with_capacity(client_ids.len());
+    for client_id in client_ids {
+        if let Some(snapshot) = client_network_stats_snapshot(client_id).await {
+            snapshots.push((client_id, snapshot));
+        }
+    }
+    snapshots
+}
+
+/// Prints each client's traffic, rtt and loss, for the server's periodic report.
+pub async fn report_client_network_stats() {
+    for (client_id, stats) in all_client_network_stats_snapshots().await {
+        println!(
+            "  client {}: rtt {:?}, loss {:.0}%, {} packets in, {} out, {} retransmits, {} queued",
+            client_id,
+            stats.rtt,
+            stats.loss * 100.0,
+            stats.packets_in,
+            stats.packets_out,
+            stats.retransmits,
+            stats.mailbox_depth
+        );
+        let dropped = stats.dropped_inbound_queue_full
+            + stats.dropped_outbound_queue_full
+            + stats.dropped_malformed
+            + stats.dropped_rate_limited;
+        if dropped > 0 {
+            println!(
+                "    dropped: {} inbound full, {} outbound full, {} malformed, {} rate limited",
+                stats.dropped_inbound_queue_full,
+                stats.dropped_outbound_queue_full,
+                stats.dropped_malformed,
+                stats.dropped_rate_limited
+            );
+        }
+    }
+}
@@ -1,16 +1,250 @@
-use std::sync::atomic::Ordering;
-
 use glam::Vec2;
+use hecs::{Entity, World};
+use raylib::prelude::Color;
+
+use crate::{
+    client_to_server::ClientToServerMessage,
+    components::{
+        CTransform, Interpolated, NetworkId, OwnerClient, Physics, Predicted, ProcessedInput, Shape,
+    },
+    game_objects::player_bundle,
+    input::InputCommand,
+    replication::{apply_state, EntityState},
+    rpc,
+    server_to_client::ServerToClientMessage,
+    settings::{
+        INTERPOLATION_DELAY_TICKS, MAX_EXTRAPOLATION_TICKS, TICKS_PER_SECOND, WORLD_MAX, WORLD_MIN,
+    },
+    snapshot::{apply_update, unwrap_tick, EntityStates},
+};
 
-use crate::{game_objects::Player, server_to_client::ServerToClientMessage};
+use crate::client_udp_networking::{
+    call, enqueue_outbound_message, CallError, INCOMING_MESSAGE_QUEUE,
+};
+use crate::state::{DespawnFade, State};
 
-use crate::client_udp_networking::{CLIENT_ID, INCOMING_MESSAGE_QUEUE};
-use crate::state::State;
+// how long a despawned entity takes to fade out
+pub const DESPAWN_FADE_FRAMES: u32 = 30;
+// corrections shorter than this are smoothed out over a few ticks, longer ones snap
+pub const PREDICTION_SNAP_DISTANCE: f32 = 16.0;
+// how much of a correction is still drawn after each tick
+const PREDICTION_SMOOTHING: f32 = 0.8;
+// about two seconds of commands, anything older the server has dropped or never got
+const MAX_PENDING_INPUTS: usize = 120;
+// how much of the gap to the newest snapshot tick our clock closes with each snapshot
+const CLOCK_CORRECTION: f64 = 0.1;
+// further off than this and the clock jumps straight to the snapshot tick
+const CLOCK_SNAP_TICKS: f64 = 30.0;
 
 pub fn step(state: &mut State) {
-    for (_, player) in state.players.iter_mut() {
-        player.step();
+    for (_, predicted) in state.world.query_mut::<&mut Predicted>() {
+        predicted.error *= PREDICTION_SMOOTHING;
+    }
+
+    for fade in state.despawn_fades.iter_mut() {
+        fade.frames_left = fade.frames_left.saturating_sub(1);
+    }
+    state.despawn_fades.retain(|fade| fade.frames_left > 0);
+}
+
+/// Moves our own players by `command` right away, the way the server will once it gets
+/// it, and keeps the command until a snapshot shows the server has simulated it.
+pub fn predict(state: &mut State, command: InputCommand) {
+    for (_, (predicted, transform, physics)) in
+        state
+            .world
+            .query_mut::<(&mut Predicted, &mut CTransform, &mut Physics)>()
+    {
+        simulate(&command, transform, physics);
+        if predicted.pending.len() == MAX_PENDING_INPUTS {
+            predicted.pending.pop_front();
+        }
+        predicted.pending.push_back(command);
+    }
+}
+
+/// One tick of a player, the same as the server's input, movement and world bounds
+/// systems.
+fn simulate(command: &InputCommand, transform: &mut CTransform, physics: &mut Physics) {
+    physics.vel = command.velocity();
+    transform.pos = (transform.pos + physics.vel).clamp(WORLD_MIN, WORLD_MAX);
+}
+
+/// Our own player has just been set back to what the server says. Replays the commands
+/// the server had not simulated yet on top, and draws the player where it was before,
+/// closing the gap over the next few ticks.
+fn reconcile(world: &mut World, entity: Entity, drawn_at: Vec2) {
+    let Ok((predicted, transform, physics, processed)) = world.query_one_mut::<(
+        &mut Predicted,
+        &mut CTransform,
+        &mut Physics,
+        Option<&ProcessedInput>,
+    )>(entity) else {
+        return;
+    };
+
+    if let Some(processed) = processed {
+        while predicted
+            .pending
+            .front()
+            .is_some_and(|command| command.sequence <= processed.sequence)
+        {
+            predicted.pending.pop_front();
+        }
+    }
+    for command in predicted.pending.iter() {
+        simulate(command, transform, physics);
+    }
+
+    predicted.error = drawn_at - transform.pos;
+    if predicted.error.length() > PREDICTION_SNAP_DISTANCE {
+        predicted.error = Vec2::ZERO;
+    }
+}
+
+/// Moves every remote entity to where it was `INTERPOLATION_DELAY_TICKS` before our
+/// estimate of the server's tick. Runs every frame, so they move smoothly however
+/// unevenly snapshots arrive.
+pub fn interpolate(state: &mut State, dt: f32) {
+    state.server_time += dt as f64 * TICKS_PER_SECOND as f64;
+    let render_tick = state.server_time - INTERPOLATION_DELAY_TICKS;
+
+    for (_, (interpolated, transform)) in state
+        .world
+        .query_mut::<(&Interpolated, &mut CTransform)>()
+        .without::<&Predicted>()
+    {
+        if let Some(pos) = interpolated.position_at(render_tick, MAX_EXTRAPOLATION_TICKS) {
+            transform.pos = pos;
+        }
+    }
+}
+
+/// Pulls our estimate of the server's tick towards a snapshot that just arrived.
+fn sync_server_time(state: &mut State, tick: u64) {
+    let behind = tick as f64 - state.server_time;
+    if behind.abs() > CLOCK_SNAP_TICKS {
+        state.server_time = tick as f64;
+    } else {
+        state.server_time += behind * CLOCK_CORRECTION;
+    }
+}
+
+/// Keeps the whole snapshot as a baseline for the deltas that follow, and tells the
+/// server it can diff against it from now on.
+fn receive_snapshot(state: &mut State, tick: u32, entities: EntityStates) {
+    apply_snapshot(state, unwrap_tick(tick, state.server_tick), &entities);
+    state.snapshot_history.push(tick, entities);
+    enqueue_outbound_message(ClientToServerMessage::SnapshotAck { tick });
+}
+
+pub fn apply_snapshot(state: &mut State, tick: u64, entities: &EntityStates) {
+    // arrived late, something newer already replaced it
+    if tick <= state.server_tick {
+        return;
+    }
+    state.server_tick = tick;
+    sync_server_time(state, tick);
+    apply_entities(state, tick, entities);
+}
+
+/// Snapshots are the truth about every entity, our own player included. Remote
+/// entities are drawn from the positions they had in recent snapshots, see
+/// `interpolate`.
+fn apply_entities(state: &mut State, tick: u64, entities: &EntityStates) {
+    // ours are never removed here, since a snapshot taken before our spawn can still
+    // be in flight when the spawn response arrives
+    let gone: Vec<u32> = state
+        .registry
+        .entity_ids()
+        .filter(|entity_id| !entities.contains_key(entity_id))
+        .filter(|&entity_id| !is_ours(state, entity_id))
+        .collect();
+    for entity_id in gone {
+        despawn_entity(state, entity_id);
+    }
+
+    for (&entity_id, entity) in entities.iter() {
+        match state.registry.entity(entity_id) {
+            Some(local) => {
+                let drawn_at = drawn_position(&state.world, local);
+                apply_state(&mut state.world, local, entity);
+                if let Some(drawn_at) = drawn_at {
+                    reconcile(&mut state.world, local, drawn_at);
+                }
+                remember_position(&mut state.world, local, tick);
+            }
+            None => {
+                let local = spawn_entity(state, entity_id, entity);
+                remember_position(&mut state.world, local, tick);
+            }
+        }
+    }
+}
+
+fn remember_position(world: &mut World, entity: Entity, tick: u64) {
+    if let Ok((interpolated, transform, physics)) =
+        world.query_one_mut::<(&mut Interpolated, &CTransform, Option<&Physics>)>(entity)
+    {
+        let vel = physics.map_or(Vec2::ZERO, |physics| physics.vel);
+        interpolated.push(tick, transform.pos, vel);
+    }
+}
+
+/// Where a predicted entity is on screen right now, None for any other entity.
+fn drawn_position(world: &World, entity: Entity) -> Option<Vec2> {
+    let predicted = world.get::<&Predicted>(entity).ok()?;
+    let transform = world.get::<&CTransform>(entity).ok()?;
+    Some(transform.pos + predicted.error)
+}
+
+fn is_ours(state: &State, entity_id: u32) -> bool {
+    let Some(entity) = state.registry.entity(entity_id) else {
+        return false;
+    };
+    let owner = state.world.get::<&OwnerClient>(entity).ok();
+    owner.is_some_and(|owner| Some(owner.client_id) == state.client_id)
+}
+
+/// Mirrors a replicated entity, plus the components only we need to draw it.
+fn spawn_entity(state: &mut State, entity_id: u32, entity: &EntityState) -> Entity {
+    let client_side = (entity_color(entity_id), Interpolated::default());
+    let local = state
+        .registry
+        .spawn_with_id(&mut state.world, entity_id, client_side);
+    apply_state(&mut state.world, local, entity);
+    local
+}
+
+/// Each entity keeps the same color, a hash of its id.
+fn entity_color(entity_id: u32) -> Color {
+    // the high bit keeps it bright against the black background
+    let hash = entity_id as u8;
+    Color::new(
+        hash.wrapping_mul(17) | 0x80,
+        hash.wrapping_mul(23) | 0x80,
+        hash.wrapping_mul(29) | 0x80,
+        255,
+    )
+}
+
+pub fn despawn_entity(state: &mut State, entity_id: u32) {
+    let Some(entity) = state.registry.entity(entity_id) else {
+        return;
+    };
+    if let Ok((transform, shape, color)) = state
+        .world
+        .query_one_mut::<(&CTransform, &Shape, &Color)>(entity)
+    {
+        state.despawn_fades.push(DespawnFade {
+            pos: transform.pos,
+            dims: shape.dims,
+            color: *color,
+            frames_left: DESPAWN_FADE_FRAMES,
+        });
     }
+    state.registry.despawn(&mut state.world, entity_id);
+    println!("entity despawned {}", entity_id);
 }
 
 // use std::time::Instant;
@@ -43,6 +277,35 @@ pub fn step(state: &mut State) {
 //     }
 // }
 
+/// Fetches the whole world in one snapshot, acknowledges it, and only then gets our
+/// own player. Anything that changes after the snapshot is already queued behind it,
+/// so once this returns the local world is complete.
+pub async fn join_game(state: &mut State, client_id: u32) -> Result<(), CallError> {
+    state.client_id = Some(client_id);
+
+    let snapshot = call(rpc::Join).await?;
+    // later ticks are unwrapped relative to this one
+    state.server_tick = snapshot.tick as u64;
+    state.server_time = state.server_tick as f64;
+    state.registry.clear(&mut state.world);
+    apply_entities(state, state.server_tick, &snapshot.entities);
+    println!("world snapshot at tick {}", snapshot.tick);
+
+    let spawned = call(rpc::AckSnapshot {
+        tick: snapshot.tick,
+    })
+    .await?;
+    let local = state.registry.spawn_with_id(
+        &mut state.world,
+        spawned.entity_id,
+        player_bundle(client_id, spawned.pos),
+    );
+    let client_side = (entity_color(spawned.entity_id), Predicted::default());
+    let _ = state.world.insert(local, client_side);
+    println!("player spawned {}", spawned.entity_id);
+    Ok(())
+}
+
 pub async fn process_message_queue(state: &mut State) {
     while let Some(message) = INCOMING_MESSAGE_QUEUE.pop() {
         match message {
@@ -58,47 +321,51 @@ pub async fn process_message_queue(state: &mut State) {
             }
             ServerToClientMessage::ClientLeft { id } => {
                 println!("Client {} left", id);
+
+                // the next snapshot despawns these too, this just does it sooner
+                let owned: Vec<u32> = state
+                    .world
+                    .query::<(&NetworkId, &OwnerClient)>()
+                    .iter()
+                    .filter(|(_, (_, owner))| owner.client_id == id)
+                    .map(|(_, (network_id, _))| network_id.entity_id)
+                    .collect();
+                for entity_id in owned {
+                    despawn_entity(state, entity_id);
+                }
             }
             ServerToClientMessage::ChatMessage { from, message } => {
                 println!("{} says: {}", from, message);
             }
-            ServerToClientMessage::SpawnPlayer {
-                owner_client_id,
-                entity_id,
-                pos,
-            } => {
-                // if the owner_client_id is our id, set out state.player_id to Some(owner_client_id)
-                let our_client_id = CLIENT_ID.load(Ordering::SeqCst);
-
-                state.players.insert(
-                    entity_id,
-                    Player {
-                        owner_client_id,
-                        entity_id,
-                        pos,
-                        vel: Vec2::new(0.0, 0.0),
-                    },
-                );
-
-                println!("player spawned {}", entity_id);
-            }
-            ServerToClientMessage::EntityPosition { entity_id, pos } => {
-                if let Some(player) = state.players.get_mut(&entity_id) {
-                    player.pos = pos;
+            ServerToClientMessage::Replication { update } => {
+                let update = update.0;
+                let empty = EntityStates::new();
+                let baseline = match update.baseline_tick {
+                    Some(baseline_tick) => state.snapshot_history.get(baseline_tick),
+                    None => Some(&empty),
+                };
+                match baseline.and_then(|baseline| apply_update(baseline, &update)) {
+                    Some(entities) => receive_snapshot(state, update.tick, entities),
+                    None => eprintln!(
+                        "Dropping snapshot {}: no baseline for tick {:?}",
+                        update.tick, update.baseline_tick
+                    ),
                 }
             }
-            ServerToClientMessage::AllPlayers { players } => {
-                for player in players {
-                    state.players.insert(
-                        player.entity_id,
-                        Player {
-                            owner_client_id: player.owner_client_id,
-                            entity_id: player.entity_id,
-                            pos: player.pos,
-                            vel: Vec2::new(0.0, 0.0),
-                        },
-                    );
-                }
+            ServerToClientMessage::ConnectionRejected { reason } => {
+                println!("Server rejected connection: {}", reason);
+                state.running = false;
+            }
+            ServerToClientMessage::Ping { .. }
+            | ServerToClientMessage::Pong { .. }
+            | ServerToClientMessage::Response { .. } => {
+                // answered by the networking layer
+            }
+            ServerToClientMessage::ServerShutdown { reason } => {
+                println!("Server shut down: {}", reason);
+                state.client_id = None;
+                state.registry.clear(&mut state.world);
+                state.disconnect_reason = Some(reason);
             }
         }
     }
@@ -1,13 +1,20 @@
-use serde::{Deserialize, Serialize};
+use crate::{bitpack::Packed, input::InputCommand, rpc::Request, wire_enum::wire_enum};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ClientToServerMessage {
-    Connect,
-    Disconnect,
-    ChatMessage { message: String },
-    RequestToSpawnPlayer,
-    RequestAllPlayers,
-    EntityPosition { entity_id: u32, pos: glam::Vec2 },
+wire_enum! {
+    #[derive(Debug, Clone)]
+    pub enum ClientToServerMessage {
+        0 => Connect,
+        1 => Disconnect,
+        2 => ChatMessage { message: String },
+        // 3 to 5 are retired, never reuse them
+        6 => ConnectRequest { protocol_version: u32 },
+        7 => Ping { sequence: u32 },
+        8 => Pong { sequence: u32 },
+        9 => Request { request_id: u32, request: Request },
+        10 => SnapshotAck { tick: u32 },
+        // 11 is retired, never reuse it
+        12 => Input { command: Packed<InputCommand> },
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1,15 +1,29 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crossbeam::queue::ArrayQueue;
 use tokio::io::{self};
-use tokio::net::UdpSocket;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
-const SERVER_ADDR: &str = "127.0.0.1:8080";
 use lazy_static::lazy_static;
 
 use crate::client_to_server::ClientToServerMessage;
-use crate::server_to_client::ServerToClientMessage;
+use crate::codec::{decode, encode, MAX_PACKET_SIZE};
+use crate::network_stats::{ConnectionStats, ConnectionStatsSnapshot, DropReason, PING_INTERVAL};
+use crate::rpc::{RpcError, RpcMethod, RpcResult, RPC_MAX_ATTEMPTS, RPC_RETRY_INTERVAL};
+use crate::server_to_client::{RejectionReason, ServerToClientMessage};
+use crate::settings::PROTOCOL_VERSION;
+use crate::traffic_capture::capture_packet;
+
+const CONNECT_REQUEST_RESEND_INTERVAL: Duration = Duration::from_millis(500);
+// per resolved address, so a dead address family does not stall the whole connect
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
 
 lazy_static! {
     pub static ref INCOMING_MESSAGE_QUEUE: Arc<ArrayQueue<ServerToClientMessage>> =
@@ -18,39 +32,243 @@ lazy_static! {
         Arc::new(ArrayQueue::new(64));
     pub static ref SERVER_DISCONNECTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     pub static ref CLIENT_ID: Arc<AtomicU32> = Arc::new(AtomicU32::new(0));
+    pub static ref CLIENT_ID_ASSIGNED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    pub static ref CONNECTION_REJECTION: Mutex<Option<RejectionReason>> = Mutex::new(None);
+    pub static ref CONNECTION_STATS: ConnectionStats = ConnectionStats::new();
+    pub static ref NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(0);
+    // calls still waiting on a response, completed straight from the rx task
+    pub static ref PENDING_CALLS: Mutex<HashMap<u32, oneshot::Sender<RpcResult>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Resolve(io::Error),
+    NoAddresses,
+    Rejected(RejectionReason),
+    TimedOut,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Resolve(e) => write!(f, "could not resolve server address: {}", e),
+            HandshakeError::NoAddresses => write!(f, "server address resolved to nothing"),
+            HandshakeError::Rejected(reason) => write!(f, "server rejected connection: {}", reason),
+            HandshakeError::TimedOut => write!(f, "server did not answer"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CallError {
+    Failed(RpcError),
+    TimedOut,
+    WrongResponse,
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Failed(e) => write!(f, "{}", e),
+            CallError::TimedOut => write!(f, "server did not respond"),
+            CallError::WrongResponse => write!(f, "server answered with the wrong response type"),
+        }
+    }
 }
 
 pub async fn disconnect_from_server() {}
 
+/// Queues a message for the tx task, counting it as dropped if the queue is full.
+pub fn enqueue_outbound_message(message: ClientToServerMessage) {
+    if OUTBOUND_MESSAGE_QUEUE.push(message).is_err() {
+        eprintln!("Outbound message queue full: dropping message");
+        CONNECTION_STATS.record_drop(DropReason::OutboundQueueFull);
+    }
+}
+
+/// Traffic, drops, rtt and loss for our connection to the server.
+pub fn connection_stats() -> ConnectionStatsSnapshot {
+    CONNECTION_STATS.snapshot(OUTBOUND_MESSAGE_QUEUE.len())
+}
+
+/// Resolves `server_addr` and tries every address it maps to, ipv6 and ipv4 alike,
+/// until one of them hands us a client id. A refusal is final.
+pub async fn connect_to_server(server_addr: &str) -> Result<u32, HandshakeError> {
+    let server_addresses: Vec<SocketAddr> = lookup_host(server_addr)
+        .await
+        .map_err(HandshakeError::Resolve)?
+        .collect();
+    if server_addresses.is_empty() {
+        return Err(HandshakeError::NoAddresses);
+    }
+
+    for server_address in server_addresses {
+        let network_tasks = match init_connection(server_address).await {
+            Ok(network_tasks) => network_tasks,
+            Err(e) => {
+                eprintln!("Error connecting to {}: {:?}", server_address, e);
+                continue;
+            }
+        };
+
+        match handshake().await {
+            Err(HandshakeError::TimedOut) => {
+                println!("no answer from {}", server_address);
+                for network_task in network_tasks {
+                    network_task.abort();
+                }
+                while OUTBOUND_MESSAGE_QUEUE.pop().is_some() {}
+            }
+            result => return result,
+        }
+    }
+    Err(HandshakeError::TimedOut)
+}
+
+/// Asks the server for a client id, resending until it answers, refuses, or we give up.
+pub async fn handshake() -> Result<u32, HandshakeError> {
+    let started = Instant::now();
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        if CLIENT_ID_ASSIGNED.load(Ordering::SeqCst) {
+            return Ok(CLIENT_ID.load(Ordering::SeqCst));
+        }
+        if let Some(reason) = *CONNECTION_REJECTION.lock().unwrap() {
+            return Err(HandshakeError::Rejected(reason));
+        }
+        if started.elapsed() > CONNECT_TIMEOUT {
+            return Err(HandshakeError::TimedOut);
+        }
+
+        let should_send = match last_sent {
+            Some(last_sent) => last_sent.elapsed() > CONNECT_REQUEST_RESEND_INTERVAL,
+            None => true,
+        };
+        if should_send {
+            if last_sent.is_some() {
+                CONNECTION_STATS.record_retransmit();
+            }
+            last_sent = Some(Instant::now());
+            enqueue_outbound_message(ClientToServerMessage::ConnectRequest {
+                protocol_version: PROTOCOL_VERSION,
+            });
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+////////////////////////    RPC    ////////////////////////
+/// Sends `request` and waits for the server to answer it. Unanswered requests are
+/// resent under the same id, so the server runs each call at most once.
+pub async fn call<M: RpcMethod>(request: M) -> Result<M::Response, CallError> {
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst);
+    let request = request.into_request();
+    let (sender, mut receiver) = oneshot::channel();
+    PENDING_CALLS.lock().unwrap().insert(request_id, sender);
+
+    for attempt in 0..RPC_MAX_ATTEMPTS {
+        if attempt > 0 {
+            CONNECTION_STATS.record_retransmit();
+        }
+        enqueue_outbound_message(ClientToServerMessage::Request {
+            request_id,
+            request: request.clone(),
+        });
+
+        match tokio::time::timeout(RPC_RETRY_INTERVAL, &mut receiver).await {
+            Ok(Ok(result)) => {
+                let response = result.map_err(CallError::Failed)?;
+                return M::from_response(response).ok_or(CallError::WrongResponse);
+            }
+            // the sender only goes away with the call itself
+            Ok(Err(_)) => break,
+            Err(_) => {}
+        }
+    }
+
+    PENDING_CALLS.lock().unwrap().remove(&request_id);
+    Err(CallError::TimedOut)
+}
+
+fn complete_call(request_id: u32, result: RpcResult) {
+    // a duplicate answer to a retried request finds nothing here and is dropped
+    if let Some(sender) = PENDING_CALLS.lock().unwrap().remove(&request_id) {
+        let _ = sender.send(result);
+    }
+}
+
 ////////////////////////    CLIENT RX/TX TASKS    ////////////////////////
 
-pub async fn init_connection() -> tokio::io::Result<()> {
-    println!("connecting");
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect(SERVER_ADDR).await?;
+pub async fn init_connection(
+    server_address: SocketAddr,
+) -> tokio::io::Result<Vec<JoinHandle<io::Result<()>>>> {
+    println!("connecting to {}", server_address);
+    let local_address: SocketAddr = match server_address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local_address).await?;
+    socket.connect(server_address).await?;
 
     println!("connected");
     let a_socket = Arc::new(socket);
 
     println!("spawning network tasks");
-    tokio::spawn(receive_incoming_messages(a_socket.clone()));
-    tokio::spawn(transmit_outbound_messages(a_socket.clone()));
-    Ok(())
+    Ok(vec![
+        tokio::spawn(receive_incoming_messages(a_socket.clone())),
+        tokio::spawn(transmit_outbound_messages(a_socket.clone())),
+        tokio::spawn(continuously_ping_server()),
+    ])
 }
 
 pub async fn receive_incoming_messages(socket: Arc<UdpSocket>) -> io::Result<()> {
-    let mut buffer = [0; 1024];
+    let mut buffer = [0; MAX_PACKET_SIZE as usize];
     loop {
         let nbytes = socket.recv(&mut buffer).await?;
-        let result: Result<ServerToClientMessage, _> = bincode::deserialize(&buffer[..nbytes]);
+        CONNECTION_STATS.record_in(nbytes);
+        capture_packet("server_to_client", &buffer[..nbytes]);
+        let result: Result<ServerToClientMessage, _> = decode(&buffer[..nbytes]);
         match result {
             Ok(message) => {
+                // the handshake and tx task watch these outside the game loop
+                match &message {
+                    ServerToClientMessage::ClientIDAssignment { new_client_id } => {
+                        CLIENT_ID.store(*new_client_id, Ordering::SeqCst);
+                        CLIENT_ID_ASSIGNED.store(true, Ordering::SeqCst);
+                    }
+                    ServerToClientMessage::ConnectionRejected { reason } => {
+                        *CONNECTION_REJECTION.lock().unwrap() = Some(*reason);
+                    }
+                    ServerToClientMessage::ServerShutdown { .. } => {
+                        SERVER_DISCONNECTED.store(true, Ordering::SeqCst);
+                    }
+                    ServerToClientMessage::Ping { sequence } => {
+                        enqueue_outbound_message(ClientToServerMessage::Pong {
+                            sequence: *sequence,
+                        });
+                        continue;
+                    }
+                    ServerToClientMessage::Pong { sequence } => {
+                        CONNECTION_STATS.finish_ping(*sequence);
+                        continue;
+                    }
+                    ServerToClientMessage::Response { request_id, result } => {
+                        complete_call(*request_id, result.clone());
+                        continue;
+                    }
+                    _ => {}
+                }
+
                 if INCOMING_MESSAGE_QUEUE.push(message).is_err() {
                     eprintln!("Inbound message queue full: dropping message");
+                    CONNECTION_STATS.record_drop(DropReason::InboundQueueFull);
                 }
             }
             Err(e) => {
-                eprintln!("Error parsing client data: {:?}", e);
+                eprintln!("Error parsing server data: {}", e);
+                CONNECTION_STATS.record_drop(DropReason::Malformed);
             }
         }
 
@@ -69,9 +287,10 @@ pub async fn transmit_outbound_messages(socket: Arc<UdpSocket>) -> io::Result<()
         // transmit any outbound messages
         if let Some(message) = OUTBOUND_MESSAGE_QUEUE.pop() {
             println!("Sending message: {:?}", message);
-            match bincode::serialize(&message) {
+            match encode(&message) {
                 Ok(binary_message) => {
                     socket.send(&binary_message).await?;
+                    CONNECTION_STATS.record_out(binary_message.len());
                 }
                 Err(e) => {
                     eprintln!("Error serializing message: {:?}", e);
@@ -82,3 +301,20 @@ pub async fn transmit_outbound_messages(socket: Arc<UdpSocket>) -> io::Result<()
         // tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
     }
 }
+
+pub async fn continuously_ping_server() -> io::Result<()> {
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+    loop {
+        interval.tick().await;
+        if SERVER_DISCONNECTED.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        // the server ignores strangers, so a ping before it has given us an id would only
+        // ever count as lost
+        if !CLIENT_ID_ASSIGNED.load(Ordering::SeqCst) {
+            continue;
+        }
+        let sequence = CONNECTION_STATS.start_ping();
+        enqueue_outbound_message(ClientToServerMessage::Ping { sequence });
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+
+use bincode::Options;
+use glam::Vec2;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    bitpack::MIN_VARINT_BITS, client_to_server::ClientToServerMessage, rpc::Response,
+    server_to_client::ServerToClientMessage, snapshot::WorldUpdate,
+};
+
+// nothing bigger than one receive buffer ever goes over the wire
+pub const MAX_PACKET_SIZE: u64 = 1024;
+pub const MAX_CHAT_MESSAGE_LENGTH: usize = 256;
+pub const MAX_SERVER_TEXT_LENGTH: usize = 256;
+// the most a world update is wrapped in: a Response with its message id, request id,
+// result and response id, and the byte count of the packed update
+const WORLD_UPDATE_OVERHEAD: u64 = 4 + 4 + 4 + 4 + 2;
+// world updates are cut down to this, see `snapshot::fit_update`
+pub const MAX_WORLD_UPDATE_BYTES: usize = (MAX_PACKET_SIZE - WORLD_UPDATE_OVERHEAD) as usize;
+// as many entities as fit in one update at their smallest: a spawn is its id and a
+// component count, an update its id and two component counts, a despawn only its id
+const MAX_SPAWNED_PER_UPDATE: usize = MAX_WORLD_UPDATE_BYTES * 8 / (2 * MIN_VARINT_BITS);
+const MAX_UPDATED_PER_UPDATE: usize = MAX_WORLD_UPDATE_BYTES * 8 / (3 * MIN_VARINT_BITS);
+const MAX_DESPAWNED_PER_UPDATE: usize = MAX_WORLD_UPDATE_BYTES * 8 / MIN_VARINT_BITS;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Bincode(bincode::Error),
+    TooLong {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+    NonFinite {
+        field: &'static str,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Bincode(e) => write!(f, "{}", e),
+            DecodeError::TooLong { field, len, max } => {
+                write!(f, "{} is {} long, max is {}", field, len, max)
+            }
+            DecodeError::NonFinite { field } => write!(f, "{} is not finite", field),
+        }
+    }
+}
+
+/// Checks the limits bincode itself cannot know about.
+pub trait Validate {
+    fn validate(&self) -> Result<(), DecodeError>;
+}
+
+////////////////////////    ENCODE / DECODE    ////////////////////////
+/// Same layout as `bincode::serialize`, but with a size limit so a forged length
+/// prefix is refused before anything gets allocated, and no trailing garbage.
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_PACKET_SIZE)
+        .reject_trailing_bytes()
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>, bincode::Error> {
+    options().serialize(message)
+}
+
+/// The one way untrusted bytes become a message, on both client and server.
+pub fn decode<T: DeserializeOwned + Validate>(bytes: &[u8]) -> Result<T, DecodeError> {
+    if bytes.len() as u64 > MAX_PACKET_SIZE {
+        return Err(DecodeError::TooLong {
+            field: "packet",
+            len: bytes.len(),
+            max: MAX_PACKET_SIZE as usize,
+        });
+    }
+    let message: T = options().deserialize(bytes).map_err(DecodeError::Bincode)?;
+    message.validate()?;
+    Ok(message)
+}
+
+////////////////////////    LIMITS    ////////////////////////
+fn check_len(field: &'static str, len: usize, max: usize) -> Result<(), DecodeError> {
+    if len > max {
+        return Err(DecodeError::TooLong { field, len, max });
+    }
+    Ok(())
+}
+
+fn check_finite(field: &'static str, v: Vec2) -> Result<(), DecodeError> {
+    if !v.is_finite() {
+        return Err(DecodeError::NonFinite { field });
+    }
+    Ok(())
+}
+
+fn check_world_update(update: &WorldUpdate) -> Result<(), DecodeError> {
+    check_len(
+        "spawned entities",
+        update.spawned.len(),
+        MAX_SPAWNED_PER_UPDATE,
+    )?;
+    check_len(
+        "updated entities",
+        update.updated.len(),
+        MAX_UPDATED_PER_UPDATE,
+    )?;
+    check_len(
+        "despawned entities",
+        update.despawned.len(),
+        MAX_DESPAWNED_PER_UPDATE,
+    )
+}
+
+impl Validate for ClientToServerMessage {
+    fn validate(&self) -> Result<(), DecodeError> {
+        match self {
+            ClientToServerMessage::ChatMessage { message } => {
+                check_len("chat message", message.len(), MAX_CHAT_MESSAGE_LENGTH)
+            }
+            ClientToServerMessage::Connect
+            | ClientToServerMessage::Disconnect
+            | ClientToServerMessage::ConnectRequest { .. }
+            | ClientToServerMessage::Ping { .. }
+            | ClientToServerMessage::Pong { .. }
+            | ClientToServerMessage::Request { .. }
+            | ClientToServerMessage::SnapshotAck { .. } => Ok(()),
+            // unpacking only ever yields finite values inside their bounds
+            ClientToServerMessage::Input { .. } => Ok(()),
+        }
+    }
+}
+
+impl Validate for ServerToClientMessage {
+    fn validate(&self) -> Result<(), DecodeError> {
+        match self {
+            ServerToClientMessage::Welcome { server_message } => check_len(
+                "welcome message",
+                server_message.len(),
+                MAX_SERVER_TEXT_LENGTH,
+            ),
+            ServerToClientMessage::ChatMessage { message, .. } => {
+                check_len("chat message", message.len(), MAX_CHAT_MESSAGE_LENGTH)
+            }
+            ServerToClientMessage::ServerShutdown { reason } => {
+                check_len("shutdown reason", reason.len(), MAX_SERVER_TEXT_LENGTH)
+            }
+            ServerToClientMessage::Replication { update } => check_world_update(&update.0),
+            ServerToClientMessage::Response { result, .. } => match result {
+                Ok(response) => response.validate(),
+                Err(_) => Ok(()),
+            },
+            ServerToClientMessage::ClientIDAssignment { .. }
+            | ServerToClientMessage::ClientJoined { .. }
+            | ServerToClientMessage::ClientLeft { .. }
+            | ServerToClientMessage::ConnectionRejected { .. }
+            | ServerToClientMessage::Ping { .. }
+            | ServerToClientMessage::Pong { .. } => Ok(()),
+        }
+    }
+}
+
+impl Validate for Response {
+    fn validate(&self) -> Result<(), DecodeError> {
+        match self {
+            Response::AckSnapshot { pos, .. } => check_finite("position", *pos),
+            Response::JoinV2 { world } => check_world_update(&world.0),
+        }
+    }
+}
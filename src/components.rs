@@ -1,6 +1,16 @@
+use std::collections::{HashMap, VecDeque};
+
 use glam::Vec2;
-use hecs::Entity;
-use raylib::prelude::Color;
+use hecs::{DynamicBundle, Entity, World};
+
+use crate::input::InputCommand;
+
+// a client that gets further ahead than this loses its oldest commands
+pub const MAX_QUEUED_INPUTS: usize = 8;
+// after this many ticks without a command the player stops, about 100ms
+pub const MAX_REPEATED_INPUTS: u32 = 6;
+// states kept per remote entity, about 800ms of snapshots
+pub const INTERPOLATION_BUFFER_LENGTH: usize = 16;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct CTransform {
@@ -12,26 +22,199 @@ pub struct CTransform {
     raylib color
 */
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Shape {
     pub dims: Vec2,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Physics {
     pub vel: Vec2,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct OwnerClient {
     pub client_id: u32,
 }
 
-//////// THIS IS FOR THE SERVER //////////
-pub struct RegistryRecord {
-    pub client_id: u32,
-    pub entity: Entity,
+/// The last input command the server simulated for this entity, so its owner knows
+/// which of its own commands a snapshot already includes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProcessedInput {
+    pub sequence: u32,
+}
+
+/// Commands from the owning client that the server has not simulated yet, one per tick.
+/// Server only.
+#[derive(Clone, Debug, Default)]
+pub struct InputQueue {
+    pending: VecDeque<InputCommand>,
+    // repeated while nothing new has arrived, so a late packet does not stop the player
+    current: InputCommand,
+    repeated: u32,
+    last_received: Option<u32>,
+}
+
+impl InputQueue {
+    /// Repeats and commands older than one already received are dropped.
+    pub fn push(&mut self, command: InputCommand) {
+        if self
+            .last_received
+            .is_some_and(|last| command.sequence <= last)
+        {
+            return;
+        }
+        self.last_received = Some(command.sequence);
+        if self.pending.len() == MAX_QUEUED_INPUTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(command);
+    }
+
+    /// The command to simulate this tick.
+    pub fn next(&mut self) -> InputCommand {
+        match self.pending.pop_front() {
+            Some(command) => {
+                self.current = command;
+                self.repeated = 0;
+            }
+            None if self.repeated < MAX_REPEATED_INPUTS => self.repeated += 1,
+            None => {
+                self.current.movement = Vec2::ZERO;
+                self.current.buttons = 0;
+            }
+        }
+        self.current
+    }
+}
+
+/// Our own player, moved ahead of the server with the commands it has not simulated
+/// yet. Client only.
+#[derive(Clone, Debug, Default)]
+pub struct Predicted {
+    pub pending: VecDeque<InputCommand>,
+    // how far the drawn position still lags behind the predicted one after a correction
+    pub error: Vec2,
+}
+
+/// Where a remote entity was in recent snapshots. It is drawn a little in the past,
+/// between two of them. Client only.
+#[derive(Clone, Debug, Default)]
+pub struct Interpolated {
+    // (tick, position, velocity), oldest first
+    samples: VecDeque<(u64, Vec2, Vec2)>,
 }
 
+impl Interpolated {
+    /// Samples arrive in tick order, since older snapshots are never applied.
+    pub fn push(&mut self, tick: u64, pos: Vec2, vel: Vec2) {
+        if self
+            .samples
+            .back()
+            .is_some_and(|&(last, _, _)| tick <= last)
+        {
+            return;
+        }
+        if self.samples.len() == INTERPOLATION_BUFFER_LENGTH {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((tick, pos, vel));
+    }
+
+    /// Where the entity was at `tick`, which may fall between ticks. Past the newest
+    /// sample it carries on at that sample's velocity for up to `max_extrapolation`
+    /// ticks.
+    pub fn position_at(&self, tick: f64, max_extrapolation: f64) -> Option<Vec2> {
+        let &(first_tick, first_pos, _) = self.samples.front()?;
+        if tick <= first_tick as f64 {
+            return Some(first_pos);
+        }
+        for pair in self.samples.iter().zip(self.samples.iter().skip(1)) {
+            let (&(from_tick, from, _), &(to_tick, to, _)) = pair;
+            if tick <= to_tick as f64 {
+                let t = (tick - from_tick as f64) / (to_tick - from_tick) as f64;
+                return Some(from.lerp(to, t as f32));
+            }
+        }
+        let &(last_tick, last_pos, last_vel) = self.samples.back()?;
+        let ahead = (tick - last_tick as f64).min(max_extrapolation);
+        Some(last_pos + last_vel * ahead as f32)
+    }
+}
+
+/// The id clients know an entity by. Only entities that have one are replicated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetworkId {
+    pub entity_id: u32,
+}
+
+/// Network ids of the entities in a world. An id is handed out once and never reused,
+/// while `hecs` is free to reuse the slot of a despawned entity. The server hands ids
+/// out with `spawn`, and clients mirror its entities with `spawn_with_id`.
 pub struct Registry {
-    pub records: Vec<RegistryRecord>,
+    next_entity_id: u32,
+    entities: HashMap<u32, Entity>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            next_entity_id: 0,
+            entities: HashMap::new(),
+        }
+    }
+
+    /// Spawns `components` with a fresh `NetworkId` and returns that id.
+    pub fn spawn(&mut self, world: &mut World, components: impl DynamicBundle) -> u32 {
+        let entity_id = self.next_entity_id;
+        self.next_entity_id += 1;
+        self.spawn_with_id(world, entity_id, components);
+        entity_id
+    }
+
+    /// Spawns `components` under an id handed out elsewhere, replacing whatever had it.
+    pub fn spawn_with_id(
+        &mut self,
+        world: &mut World,
+        entity_id: u32,
+        components: impl DynamicBundle,
+    ) -> Entity {
+        self.despawn(world, entity_id);
+        let entity = world.spawn(components);
+        world
+            .insert_one(entity, NetworkId { entity_id })
+            .expect("entity was just spawned");
+        self.entities.insert(entity_id, entity);
+        entity
+    }
+
+    pub fn despawn(&mut self, world: &mut World, entity_id: u32) {
+        if let Some(entity) = self.entities.remove(&entity_id) {
+            let _ = world.despawn(entity);
+        }
+    }
+
+    pub fn entity(&self, entity_id: u32) -> Option<Entity> {
+        self.entities.get(&entity_id).copied()
+    }
+
+    pub fn contains(&self, entity_id: u32) -> bool {
+        self.entities.contains_key(&entity_id)
+    }
+
+    pub fn entity_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entities.keys().copied()
+    }
+
+    pub fn clear(&mut self, world: &mut World) {
+        for (_, entity) in self.entities.drain() {
+            let _ = world.despawn(entity);
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -1,21 +1,63 @@
+use glam::Vec2;
 use raylib::prelude::*;
 
-use crate::state::State;
+use crate::{
+    client_game::DESPAWN_FADE_FRAMES,
+    client_udp_networking::connection_stats,
+    components::{CTransform, Predicted, Shape},
+    state::State,
+};
 
 pub fn draw(state: &State, d: &mut RaylibTextureMode<RaylibDrawHandle>) {
     d.draw_text("Multiplayer!", 12, 12, 12, Color::WHITE);
+    draw_connection_stats(d);
+    if let Some(reason) = &state.disconnect_reason {
+        d.draw_text(&format!("Disconnected: {}", reason), 12, 40, 10, Color::RED);
+    }
     let mouse_pos = d.get_mouse_position();
     d.draw_circle(mouse_pos.x as i32, mouse_pos.y as i32, 6.0, Color::GREEN);
 
-    // render all players
-    for (_, player) in state.players.iter() {
-        // color is a hash of the player id
-        let color = Color::new(
-            (player.entity_id as u8).wrapping_mul(17),
-            (player.entity_id as u8).wrapping_mul(23),
-            (player.entity_id as u8).wrapping_mul(29),
-            255,
-        );
-        d.draw_circle(player.pos.x as i32, player.pos.y as i32, 6.0, Color::BLUE);
+    // render everything that has a place, a size and a color
+    for (_, (transform, shape, color, predicted)) in state
+        .world
+        .query::<(&CTransform, &Shape, &Color, Option<&Predicted>)>()
+        .iter()
+    {
+        // what is left of the last correction to our own player
+        let error = predicted.map_or(Vec2::ZERO, |predicted| predicted.error);
+        draw_shape(d, transform.pos + error, shape.dims, *color);
+    }
+
+    // despawned entities fade out where they were last seen
+    for fade in state.despawn_fades.iter() {
+        let mut color = fade.color;
+        color.a = (color.a as u32 * fade.frames_left / DESPAWN_FADE_FRAMES) as u8;
+        draw_shape(d, fade.pos, fade.dims, color);
     }
 }
+
+/// Round trip, loss and how much is waiting to go out, for our connection to the server.
+fn draw_connection_stats(d: &mut RaylibTextureMode<RaylibDrawHandle>) {
+    let stats = connection_stats();
+    let rtt = match stats.rtt {
+        Some(rtt) => format!("{}ms", rtt.as_millis()),
+        None => "-".to_string(),
+    };
+    let text = format!(
+        "rtt {} loss {:.0}% queued {}",
+        rtt,
+        stats.loss * 100.0,
+        stats.mailbox_depth
+    );
+    d.draw_text(&text, 12, 26, 10, Color::GRAY);
+}
+
+fn draw_shape(d: &mut RaylibTextureMode<RaylibDrawHandle>, pos: Vec2, dims: Vec2, color: Color) {
+    d.draw_ellipse(
+        pos.x as i32,
+        pos.y as i32,
+        dims.x / 2.0,
+        dims.y / 2.0,
+        color,
+    );
+}
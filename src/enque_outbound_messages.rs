@@ -1,6 +1,7 @@
+use crate::network_stats::DropReason;
 use crate::server_to_client::ServerToClientMessage;
 
-use crate::bookkeeping::CLIENT_OUTBOUND_MAILBOXES;
+use crate::bookkeeping::{client_network_stats, has_joined, CLIENT_OUTBOUND_MAILBOXES};
 
 ////////////////////////    ENQUEUE OUTBOUND MESSAGES    ////////////////////////
 pub async fn send_to_one_client(client_id: u32, message: ServerToClientMessage) {
@@ -8,29 +9,57 @@ pub async fn send_to_one_client(client_id: u32, message: ServerToClientMessage)
     if let Some(queue) = clients_read.get(&client_id) {
         if queue.push(message).is_err() {
             eprintln!("Failed to enqueue message for client {}", client_id);
+            record_outbound_drop(client_id).await;
         }
     } else {
         eprintln!("Failed to find client {}", client_id);
     }
 }
 
+/// Game broadcasts only reach clients that have joined, everyone else is still
+/// waiting on their snapshot and would only get half a world.
 pub async fn broadcast_to_all_except(sender_id: u32, message: ServerToClientMessage) {
     let clients_read = CLIENT_OUTBOUND_MAILBOXES.read().await;
     for (&client_id, queue) in clients_read.iter() {
         if client_id == sender_id {
             continue; // Skip the sender
         }
+        if !has_joined(client_id) {
+            continue;
+        }
         if queue.push(message.clone()).is_err() {
             eprintln!("Failed to enqueue message for client {}", client_id);
+            record_outbound_drop(client_id).await;
         }
     }
 }
 
 pub async fn broadcast_to_all(message: ServerToClientMessage) {
     let clients_read = CLIENT_OUTBOUND_MAILBOXES.read().await;
-    for (_, queue) in clients_read.iter() {
+    for (&client_id, queue) in clients_read.iter() {
+        if !has_joined(client_id) {
+            continue;
+        }
         if queue.push(message.clone()).is_err() {
-            eprintln!("Failed to enqueue message for client");
+            eprintln!("Failed to enqueue message for client {}", client_id);
+            record_outbound_drop(client_id).await;
         }
     }
 }
+
+/// Every connection, joined or not. For things like shutdown that are not about the world.
+pub async fn broadcast_to_all_connected(message: ServerToClientMessage) {
+    let clients_read = CLIENT_OUTBOUND_MAILBOXES.read().await;
+    for (&client_id, queue) in clients_read.iter() {
+        if queue.push(message.clone()).is_err() {
+            eprintln!("Failed to enqueue message for client {}", client_id);
+            record_outbound_drop(client_id).await;
+        }
+    }
+}
+
+async fn record_outbound_drop(client_id: u32) {
+    if let Some(stats) = client_network_stats(client_id).await {
+        stats.record_drop(DropReason::OutboundQueueFull);
+    }
+}
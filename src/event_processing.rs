@@ -1,33 +1,44 @@
+use glam::Vec2;
 use raylib::prelude::*;
 
-use crate::state::State;
-
-const PLAYER_SPEED: f32 = 1.0;
+use crate::{
+    input::{InputCommand, BUTTON_SPRINT},
+    state::State,
+};
 
 pub fn process_events_and_input(rl: &mut RaylibHandle, state: &mut State) {
     if rl.is_key_pressed(raylib::consts::KeyboardKey::KEY_ESCAPE) {
         state.running = false;
     }
+}
+
+/// What the player is pressing this tick, numbered after the last command we sent.
+pub fn sample_input(rl: &RaylibHandle, state: &mut State) -> InputCommand {
+    let mut movement = Vec2::ZERO;
 
-    for (eid, mut player) in &mut state.players {
-        if let Some(client_id) = state.client_id {
-            if player.owner_client_id != client_id {
-                continue;
-            }
-        }
+    // Handle player movement with WASD keys
+    if rl.is_key_down(raylib::consts::KeyboardKey::KEY_W) {
+        movement.y -= 1.0;
+    }
+    if rl.is_key_down(raylib::consts::KeyboardKey::KEY_S) {
+        movement.y += 1.0;
+    }
+    if rl.is_key_down(raylib::consts::KeyboardKey::KEY_A) {
+        movement.x -= 1.0;
+    }
+    if rl.is_key_down(raylib::consts::KeyboardKey::KEY_D) {
+        movement.x += 1.0;
+    }
+
+    let mut buttons = 0;
+    if rl.is_key_down(raylib::consts::KeyboardKey::KEY_LEFT_SHIFT) {
+        buttons |= BUTTON_SPRINT;
+    }
 
-        // Handle player movement with WASD keys
-        if rl.is_key_down(raylib::consts::KeyboardKey::KEY_W) {
-            player.pos.y -= PLAYER_SPEED;
-        }
-        if rl.is_key_down(raylib::consts::KeyboardKey::KEY_S) {
-            player.pos.y += PLAYER_SPEED;
-        }
-        if rl.is_key_down(raylib::consts::KeyboardKey::KEY_A) {
-            player.pos.x -= PLAYER_SPEED;
-        }
-        if rl.is_key_down(raylib::consts::KeyboardKey::KEY_D) {
-            player.pos.x += PLAYER_SPEED;
-        }
+    state.input_sequence = state.input_sequence.wrapping_add(1);
+    InputCommand {
+        sequence: state.input_sequence,
+        movement,
+        buttons,
     }
 }
@@ -1,6 +1,28 @@
 use glam::Vec2;
 use serde::{Deserialize, Serialize};
 
+use crate::components::{CTransform, OwnerClient, Physics, Shape};
+
+// players are drawn as a circle this wide
+pub const PLAYER_SIZE: Vec2 = Vec2::new(12.0, 12.0);
+
+/// The components a player entity is spawned with.
+pub fn player_bundle(owner_client_id: u32, pos: Vec2) -> (CTransform, Physics, Shape, OwnerClient) {
+    (
+        CTransform {
+            pos,
+            rot: Vec2::ZERO,
+        },
+        Physics { vel: Vec2::ZERO },
+        Shape { dims: PLAYER_SIZE },
+        OwnerClient {
+            client_id: owner_client_id,
+        },
+    )
+}
+
+/// The old wire layout of a player, still read by retired messages and used for the
+/// state file.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Player {
     pub owner_client_id: u32,
@@ -8,17 +30,3 @@ pub struct Player {
     pub pos: Vec2,
     pub vel: Vec2,
 }
-impl Player {
-    pub fn new(owner_client_id: u32, id: u32) -> Self {
-        Self {
-            owner_client_id,
-            entity_id: id,
-            pos: Vec2::ZERO,
-            vel: Vec2::ZERO,
-        }
-    }
-
-    pub fn step(&mut self) {
-        self.pos += self.vel;
-    }
-}
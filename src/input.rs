@@ -0,0 +1,57 @@
+use glam::Vec2;
+
+use crate::{
+    bitpack::{BitPack, BitReader, BitWriter, Quantization, Vec2Quantization},
+    settings::{INPUT_AXIS_PRECISION, PLAYER_SPEED, SPRINT_MULTIPLIER},
+};
+
+// one bit each, in the order they go on the wire
+pub const BUTTON_SPRINT: u8 = 1 << 0;
+const BUTTON_BITS: u32 = 8;
+
+const AXIS_QUANTIZATION: Vec2Quantization = Vec2Quantization {
+    x: Quantization::new(-1.0, 1.0, INPUT_AXIS_PRECISION),
+    y: Quantization::new(-1.0, 1.0, INPUT_AXIS_PRECISION),
+};
+
+/// What a player is pressing during one tick. The client numbers its commands so the
+/// server can drop repeats and ones that arrive out of order.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct InputCommand {
+    pub sequence: u32,
+    // each axis in -1..=1, y pointing down the screen like positions
+    pub movement: Vec2,
+    pub buttons: u8,
+}
+
+impl InputCommand {
+    pub fn is_pressed(&self, button: u8) -> bool {
+        self.buttons & button != 0
+    }
+
+    /// How far this command moves a player in one tick. Diagonals are no faster than
+    /// straight lines.
+    pub fn velocity(&self) -> Vec2 {
+        let mut speed = PLAYER_SPEED;
+        if self.is_pressed(BUTTON_SPRINT) {
+            speed *= SPRINT_MULTIPLIER;
+        }
+        self.movement.clamp_length_max(1.0) * speed
+    }
+}
+
+impl BitPack for InputCommand {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write(self.sequence as u64, 32);
+        AXIS_QUANTIZATION.write(writer, self.movement);
+        writer.write(self.buttons as u64, BUTTON_BITS);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(InputCommand {
+            sequence: reader.read(32)? as u32,
+            movement: AXIS_QUANTIZATION.read(reader)?,
+            buttons: reader.read(BUTTON_BITS)? as u8,
+        })
+    }
+}
@@ -0,0 +1,224 @@
+use std::{collections::VecDeque, time::Duration};
+
+use glam::Vec2;
+use hecs::World;
+
+use crate::{
+    components::{CTransform, NetworkId, Shape},
+    settings::{INTERPOLATION_DELAY_TICKS, MAX_REWIND_TICKS},
+};
+
+/// Where one entity was at the end of a tick, and how big it was.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PastTransform {
+    pub entity_id: u32,
+    pub pos: Vec2,
+    pub dims: Vec2,
+}
+
+/// The transforms of every replicated entity for the last `MAX_REWIND_TICKS` ticks, so
+/// a hit can be judged against the world as a lagging client saw it rather than as it
+/// is now.
+pub struct TransformHistory {
+    // oldest first, one entry per tick
+    ticks: VecDeque<(u64, Vec<PastTransform>)>,
+}
+
+impl TransformHistory {
+    pub fn new() -> Self {
+        Self {
+            ticks: VecDeque::with_capacity(MAX_REWIND_TICKS as usize + 1),
+        }
+    }
+
+    /// Remembers where everything is at the end of `tick`, forgetting the oldest tick
+    /// once the window is full.
+    pub fn record(&mut self, tick: u64, world: &World) {
+        if self.ticks.len() > MAX_REWIND_TICKS as usize {
+            self.ticks.pop_front();
+        }
+        let transforms = world
+            .query::<(&NetworkId, &CTransform, &Shape)>()
+            .iter()
+            .map(|(_, (network_id, transform, shape))| PastTransform {
+                entity_id: network_id.entity_id,
+                pos: transform.pos,
+                dims: shape.dims,
+            })
+            .collect();
+        self.ticks.push_back((tick, transforms));
+    }
+
+    /// The transforms at `tick`, or at the oldest tick still kept if it is further back.
+    pub fn at(&self, tick: u64) -> Option<&[PastTransform]> {
+        let &(oldest, _) = self.ticks.front()?;
+        let tick = tick.max(oldest);
+        self.ticks
+            .iter()
+            .find(|(t, _)| *t == tick)
+            .map(|(_, transforms)| transforms.as_slice())
+    }
+
+    /// Entities whose shape contained `point` at `tick`. Shapes are ellipses filling
+    /// their dims, the same as clients draw them.
+    pub fn hit_test(&self, tick: u64, point: Vec2) -> Vec<u32> {
+        let Some(transforms) = self.at(tick) else {
+            return Vec::new();
+        };
+        transforms
+            .iter()
+            .filter(|past| {
+                let radii = past.dims / 2.0;
+                if radii.cmple(Vec2::ZERO).any() {
+                    return false;
+                }
+                ((point - past.pos) / radii).length_squared() <= 1.0
+            })
+            .map(|past| past.entity_id)
+            .collect()
+    }
+
+    /// Entities whose shape contained `point` as a client with round trip `rtt` saw them,
+    /// for something it did that reached us at `now`.
+    pub fn hit_test_as_seen(
+        &self,
+        now: u64,
+        rtt: Option<Duration>,
+        ticks_per_second: u32,
+        point: Vec2,
+    ) -> Vec<u32> {
+        self.hit_test(rewind_tick(now, rtt, ticks_per_second), point)
+    }
+}
+
+impl Default for TransformHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The tick a client was looking at when it sent something that reached us at `now`.
+/// Its snapshots took half its round trip to arrive and its request the other half,
+/// and it draws other entities `INTERPOLATION_DELAY_TICKS` behind the newest snapshot.
+/// Never further back than `MAX_REWIND_TICKS`, so a very laggy client cannot hit
+/// someone who has long since moved on.
+pub fn rewind_tick(now: u64, rtt: Option<Duration>, ticks_per_second: u32) -> u64 {
+    let latency_ticks = rtt.map_or(0.0, |rtt| rtt.as_secs_f64() * ticks_per_second as f64);
+    let rewind = (latency_ticks + INTERPOLATION_DELAY_TICKS).round() as u64;
+    now.saturating_sub(rewind.min(MAX_REWIND_TICKS as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMS: Vec2 = Vec2::new(12.0, 8.0);
+
+    /// A history of one entity that moves one unit right each tick, so it is at x = tick.
+    fn moving_entity_history(ticks: std::ops::RangeInclusive<u64>) -> TransformHistory {
+        let mut world = World::new();
+        let entity = world.spawn((
+            NetworkId { entity_id: 0 },
+            CTransform {
+                pos: Vec2::ZERO,
+                rot: Vec2::ZERO,
+            },
+            Shape { dims: DIMS },
+        ));
+        let mut history = TransformHistory::new();
+        for tick in ticks {
+            world.get::<&mut CTransform>(entity).unwrap().pos = Vec2::new(tick as f32, 0.0);
+            history.record(tick, &world);
+        }
+        history
+    }
+
+    fn x_at(history: &TransformHistory, tick: u64) -> Option<f32> {
+        history.at(tick).map(|transforms| transforms[0].pos.x)
+    }
+
+    #[test]
+    fn only_the_rewind_window_is_kept() {
+        let history = moving_entity_history(1..=100);
+        assert_eq!(history.ticks.len(), MAX_REWIND_TICKS as usize + 1);
+        assert_eq!(x_at(&history, 100 - MAX_REWIND_TICKS as u64), Some(70.0));
+    }
+
+    #[test]
+    fn ticks_older_than_the_window_give_the_oldest_kept() {
+        let history = moving_entity_history(1..=100);
+        let oldest = 100 - MAX_REWIND_TICKS as u64;
+        assert_eq!(x_at(&history, 1), Some(oldest as f32));
+        assert_eq!(x_at(&history, oldest - 1), Some(oldest as f32));
+    }
+
+    #[test]
+    fn ticks_newer_than_the_newest_give_nothing() {
+        let history = moving_entity_history(1..=100);
+        assert_eq!(x_at(&history, 100), Some(100.0));
+        assert_eq!(x_at(&history, 101), None);
+        assert!(TransformHistory::new().at(0).is_none());
+    }
+
+    #[test]
+    fn ticks_in_the_window_give_that_tick() {
+        let history = moving_entity_history(1..=100);
+        assert_eq!(x_at(&history, 85), Some(85.0));
+    }
+
+    #[test]
+    fn hits_count_on_the_ellipse_boundary_and_not_past_it() {
+        let history = moving_entity_history(0..=0);
+        let radii = DIMS / 2.0;
+
+        assert_eq!(history.hit_test(0, Vec2::ZERO), vec![0]);
+        assert_eq!(history.hit_test(0, Vec2::new(radii.x, 0.0)), vec![0]);
+        assert_eq!(history.hit_test(0, Vec2::new(0.0, -radii.y)), vec![0]);
+        assert!(history
+            .hit_test(0, Vec2::new(radii.x + 0.01, 0.0))
+            .is_empty());
+        assert!(history
+            .hit_test(0, Vec2::new(0.0, radii.y + 0.01))
+            .is_empty());
+        // inside the bounding box but outside the ellipse
+        assert!(history.hit_test(0, radii * 0.9).is_empty());
+    }
+
+    #[test]
+    fn hits_are_judged_where_the_entity_was() {
+        let history = moving_entity_history(1..=100);
+        assert_eq!(history.hit_test(80, Vec2::new(80.0, 0.0)), vec![0]);
+        assert!(history.hit_test(80, Vec2::new(100.0, 0.0)).is_empty());
+    }
+
+    #[test]
+    fn hits_are_judged_as_a_lagging_client_saw_them() {
+        let history = moving_entity_history(1..=100);
+
+        // 100ms is 6 ticks at 60 a second, plus 6 of interpolation delay
+        let rtt = Some(Duration::from_millis(100));
+        let seen = history.hit_test_as_seen(100, rtt, 60, Vec2::new(88.0, 0.0));
+        assert_eq!(seen, vec![0]);
+        assert!(history
+            .hit_test_as_seen(100, rtt, 60, Vec2::new(100.0, 0.0))
+            .is_empty());
+    }
+
+    #[test]
+    fn rewinds_by_latency_and_interpolation_delay() {
+        let delay = INTERPOLATION_DELAY_TICKS as u64;
+        assert_eq!(rewind_tick(1000, None, 60), 1000 - delay);
+
+        // 100ms is 6 ticks at 60 a second
+        let rtt = Some(Duration::from_millis(100));
+        assert_eq!(rewind_tick(1000, rtt, 60), 1000 - delay - 6);
+    }
+
+    #[test]
+    fn rewinds_no_further_than_the_window() {
+        let rtt = Some(Duration::from_secs(5));
+        assert_eq!(rewind_tick(1000, rtt, 60), 1000 - MAX_REWIND_TICKS as u64);
+        assert_eq!(rewind_tick(3, rtt, 60), 0);
+        assert_eq!(rewind_tick(0, None, 60), 0);
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+// a ping that has not been answered by now counts as lost
+pub const PING_TIMEOUT: Duration = Duration::from_secs(3);
+// weight of the newest sample in the smoothed rtt
+const RTT_SMOOTHING: f32 = 0.125;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    InboundQueueFull,
+    OutboundQueueFull,
+    Malformed,
+    RateLimited,
+}
+
+////////////////////////    PER CONNECTION COUNTERS    ////////////////////////
+/// Traffic counters for one connection. The server keeps one per client, the client
+/// keeps one for its connection to the server.
+pub struct ConnectionStats {
+    pub packets_in: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub packets_out: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub dropped_inbound_queue_full: AtomicU64,
+    pub dropped_outbound_queue_full: AtomicU64,
+    pub dropped_malformed: AtomicU64,
+    pub dropped_rate_limited: AtomicU64,
+    pub retransmits: AtomicU64,
+    pings: Mutex<PingTracker>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStatsSnapshot {
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    pub dropped_inbound_queue_full: u64,
+    pub dropped_outbound_queue_full: u64,
+    pub dropped_malformed: u64,
+    pub dropped_rate_limited: u64,
+    pub retransmits: u64,
+    pub mailbox_depth: usize,
+    pub rtt: Option<Duration>,
+    pub loss: f32,
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        Self {
+            packets_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            packets_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            dropped_inbound_queue_full: AtomicU64::new(0),
+            dropped_outbound_queue_full: AtomicU64::new(0),
+            dropped_malformed: AtomicU64::new(0),
+            dropped_rate_limited: AtomicU64::new(0),
+            retransmits: AtomicU64::new(0),
+            pings: Mutex::new(PingTracker::new()),
+        }
+    }
+
+    pub fn record_in(&self, nbytes: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(nbytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_out(&self, nbytes: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(nbytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_drop(&self, reason: DropReason) {
+        let counter = match reason {
+            DropReason::InboundQueueFull => &self.dropped_inbound_queue_full,
+            DropReason::OutboundQueueFull => &self.dropped_outbound_queue_full,
+            DropReason::Malformed => &self.dropped_malformed,
+            DropReason::RateLimited => &self.dropped_rate_limited,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retransmit(&self) {
+        self.retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the sequence number to put in the outgoing ping.
+    pub fn start_ping(&self) -> u32 {
+        self.pings.lock().unwrap().start_ping(Instant::now())
+    }
+
+    pub fn finish_ping(&self, sequence: u32) {
+        self.pings
+            .lock()
+            .unwrap()
+            .finish_ping(sequence, Instant::now());
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.pings.lock().unwrap().smoothed_rtt
+    }
+
+    /// True once every ping for `PING_TIMEOUT` has gone unanswered, like from a peer
+    /// that crashed.
+    pub fn is_unresponsive(&self) -> bool {
+        self.pings.lock().unwrap().is_unresponsive(Instant::now())
+    }
+
+    pub fn snapshot(&self, mailbox_depth: usize) -> ConnectionStatsSnapshot {
+        let (rtt, loss) = {
+            let mut pings = self.pings.lock().unwrap();
+            pings.expire(Instant::now());
+            (pings.smoothed_rtt, pings.loss())
+        };
+        ConnectionStatsSnapshot {
+            packets_in: self.packets_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            packets_out: self.packets_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            dropped_inbound_queue_full: self.dropped_inbound_queue_full.load(Ordering::Relaxed),
+            dropped_outbound_queue_full: self.dropped_outbound_queue_full.load(Ordering::Relaxed),
+            dropped_malformed: self.dropped_malformed.load(Ordering::Relaxed),
+            dropped_rate_limited: self.dropped_rate_limited.load(Ordering::Relaxed),
+            retransmits: self.retransmits.load(Ordering::Relaxed),
+            mailbox_depth,
+            rtt,
+            loss,
+        }
+    }
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+////////////////////////    RTT AND LOSS    ////////////////////////
+struct PingTracker {
+    next_sequence: u32,
+    outstanding: VecDeque<(u32, Instant)>,
+    answered: u64,
+    lost: u64,
+    smoothed_rtt: Option<Duration>,
+    // when the oldest ping sent since the last answer went out
+    unanswered_since: Option<Instant>,
+}
+
+impl PingTracker {
+    fn new() -> Self {
+        Self {
+            next_sequence: 0,
+            outstanding: VecDeque::new(),
+            answered: 0,
+            lost: 0,
+            smoothed_rtt: None,
+            unanswered_since: None,
+        }
+    }
+
+    fn start_ping(&mut self, now: Instant) -> u32 {
+        self.expire(now);
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.outstanding.push_back((sequence, now));
+        self.unanswered_since.get_or_insert(now);
+        sequence
+    }
+
+    fn finish_ping(&mut self, sequence: u32, now: Instant) {
+        let position = self
+            .outstanding
+            .iter()
+            .position(|&(outstanding_sequence, _)| outstanding_sequence == sequence);
+        let sent_at = match position.and_then(|position| self.outstanding.remove(position)) {
+            Some((_, sent_at)) => sent_at,
+            None => return, // duplicate, or already counted as lost
+        };
+
+        self.answered += 1;
+        self.unanswered_since = None;
+        let sample = now.saturating_duration_since(sent_at);
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed_rtt) => {
+                smoothed_rtt.mul_f32(1.0 - RTT_SMOOTHING) + sample.mul_f32(RTT_SMOOTHING)
+            }
+            None => sample,
+        });
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(_, sent_at)) = self.outstanding.front() {
+            if now.saturating_duration_since(sent_at) < PING_TIMEOUT {
+                break;
+            }
+            self.outstanding.pop_front();
+            self.lost += 1;
+        }
+    }
+
+    fn is_unresponsive(&self, now: Instant) -> bool {
+        self.unanswered_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= PING_TIMEOUT)
+    }
+
+    fn loss(&self) -> f32 {
+        let total = self.answered + self.lost;
+        if total == 0 {
+            return 0.0;
+        }
+        self.lost as f32 / total as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_answer_sets_the_rtt() {
+        let mut pings = PingTracker::new();
+        let now = Instant::now();
+        let sequence = pings.start_ping(now);
+        pings.finish_ping(sequence, now + Duration::from_millis(80));
+        assert_eq!(pings.smoothed_rtt, Some(Duration::from_millis(80)));
+        assert_eq!(pings.loss(), 0.0);
+    }
+
+    #[test]
+    fn later_answers_are_smoothed() {
+        let mut pings = PingTracker::new();
+        let now = Instant::now();
+        let first = pings.start_ping(now);
+        pings.finish_ping(first, now + Duration::from_millis(80));
+        let second = pings.start_ping(now + Duration::from_secs(1));
+        pings.finish_ping(
+            second,
+            now + Duration::from_secs(1) + Duration::from_millis(160),
+        );
+
+        // an eighth of the way from 80ms towards 160ms
+        let rtt = pings.smoothed_rtt.unwrap().as_secs_f32();
+        assert!((rtt - 0.090).abs() < 0.0001, "rtt {}", rtt);
+    }
+
+    #[test]
+    fn unknown_and_duplicate_answers_are_ignored() {
+        let mut pings = PingTracker::new();
+        let now = Instant::now();
+        let sequence = pings.start_ping(now);
+        pings.finish_ping(sequence + 1, now + Duration::from_millis(10));
+        assert_eq!(pings.smoothed_rtt, None);
+
+        pings.finish_ping(sequence, now + Duration::from_millis(80));
+        pings.finish_ping(sequence, now + Duration::from_millis(500));
+        assert_eq!(pings.smoothed_rtt, Some(Duration::from_millis(80)));
+        assert_eq!(pings.answered, 1);
+    }
+
+    #[test]
+    fn unanswered_pings_are_lost_after_the_timeout() {
+        let mut pings = PingTracker::new();
+        let now = Instant::now();
+        let lost = pings.start_ping(now);
+        let answered = pings.start_ping(now + Duration::from_secs(1));
+        pings.finish_ping(
+            answered,
+            now + Duration::from_secs(1) + Duration::from_millis(50),
+        );
+
+        pings.expire(now + PING_TIMEOUT - Duration::from_millis(1));
+        assert_eq!(pings.lost, 0);
+        pings.expire(now + PING_TIMEOUT);
+        assert_eq!(pings.lost, 1);
+        assert_eq!(pings.loss(), 0.5);
+
+        // too late, it already counts as lost
+        pings.finish_ping(lost, now + PING_TIMEOUT + Duration::from_millis(1));
+        assert_eq!(pings.answered, 1);
+        assert_eq!(pings.loss(), 0.5);
+    }
+
+    #[test]
+    fn a_peer_that_answers_nothing_is_unresponsive() {
+        let mut pings = PingTracker::new();
+        let now = Instant::now();
+        assert!(!pings.is_unresponsive(now + PING_TIMEOUT));
+
+        pings.start_ping(now);
+        pings.start_ping(now + Duration::from_secs(1));
+        assert!(!pings.is_unresponsive(now + PING_TIMEOUT - Duration::from_millis(1)));
+        assert!(pings.is_unresponsive(now + PING_TIMEOUT));
+    }
+
+    #[test]
+    fn any_answer_makes_a_peer_responsive_again() {
+        let mut pings = PingTracker::new();
+        let now = Instant::now();
+        pings.start_ping(now);
+        let answered = pings.start_ping(now + Duration::from_secs(1));
+        pings.finish_ping(answered, now + Duration::from_secs(2));
+        assert!(!pings.is_unresponsive(now + PING_TIMEOUT));
+
+        // counts from the first ping after the answer
+        let later = now + Duration::from_secs(3);
+        pings.start_ping(later);
+        assert!(!pings.is_unresponsive(later + PING_TIMEOUT - Duration::from_millis(1)));
+        assert!(pings.is_unresponsive(later + PING_TIMEOUT));
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    client_to_server::{ClientToServerMessage, ClientToServerMessageBundle},
+    server_stats::{increment, SERVER_STATS},
+    server_udp_networking::INCOMING_MESSAGE_QUEUE,
+};
+
+// packets per second any one source ip may send, with some burst headroom
+pub const PACKETS_PER_SECOND_PER_ADDRESS: f32 = 240.0;
+pub const PACKET_BURST_PER_ADDRESS: f32 = 480.0;
+
+// new client ids any one source ip may create
+pub const CONNECTION_ATTEMPTS_PER_SECOND_PER_ADDRESS: f32 = 0.5;
+pub const CONNECTION_ATTEMPT_BURST_PER_ADDRESS: f32 = 4.0;
+
+// clients that have been given an id but have not spoken since
+pub const MAX_PENDING_CONNECTIONS: usize = 64;
+pub const PENDING_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how many rate limit hits before an address gets blocklisted, and for how long
+pub const BLOCKLIST_VIOLATION_THRESHOLD: u32 = 256;
+pub const VIOLATION_FORGET_AFTER: Duration = Duration::from_secs(10);
+pub const BLOCKLIST_DURATION: Duration = Duration::from_secs(60);
+
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    pub static ref ADDRESS_LIMITS: Mutex<HashMap<IpAddr, AddressLimits>> =
+        Mutex::new(HashMap::new());
+    pub static ref BLOCKLIST: RwLock<HashMap<IpAddr, Instant>> = RwLock::new(HashMap::new());
+    pub static ref PENDING_CONNECTIONS: RwLock<HashMap<u32, Instant>> = RwLock::new(HashMap::new());
+}
+
+////////////////////////    TOKEN BUCKET    ////////////////////////
+pub struct TokenBucket {
+    capacity: f32,
+    tokens: f32,
+    refill_per_second: f32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f32, refill_per_second: f32) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_per_second,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f32();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub struct AddressLimits {
+    pub packets: TokenBucket,
+    pub connection_attempts: TokenBucket,
+    pub violations: u32,
+    pub last_violation: Option<Instant>,
+    pub last_seen: Instant,
+}
+
+impl AddressLimits {
+    pub fn new() -> Self {
+        Self {
+            packets: TokenBucket::new(PACKET_BURST_PER_ADDRESS, PACKETS_PER_SECOND_PER_ADDRESS),
+            connection_attempts: TokenBucket::new(
+                CONNECTION_ATTEMPT_BURST_PER_ADDRESS,
+                CONNECTION_ATTEMPTS_PER_SECOND_PER_ADDRESS,
+            ),
+            violations: 0,
+            last_violation: None,
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// Returns true if this violation tipped the address over the blocklist threshold.
+    fn record_violation(&mut self, now: Instant) -> bool {
+        self.violations += 1;
+        self.last_violation = Some(now);
+        if self.violations >= BLOCKLIST_VIOLATION_THRESHOLD {
+            self.violations = 0;
+            return true;
+        }
+        false
+    }
+}
+
+impl Default for AddressLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+////////////////////////    ADMISSION CHECKS    ////////////////////////
+pub async fn is_blocklisted(ip: IpAddr) -> bool {
+    is_blocklisted_at(ip, Instant::now()).await
+}
+
+async fn is_blocklisted_at(ip: IpAddr, now: Instant) -> bool {
+    let blocklist_read = BLOCKLIST.read().await;
+    match blocklist_read.get(&ip) {
+        Some(&until) => now < until,
+        None => false,
+    }
+}
+
+pub async fn blocklist(ip: IpAddr) {
+    let until = Instant::now() + BLOCKLIST_DURATION;
+    BLOCKLIST.write().await.insert(ip, until);
+    increment(&SERVER_STATS.addresses_blocklisted);
+    eprintln!("Blocklisting {} for {:?}", ip, BLOCKLIST_DURATION);
+}
+
+/// Spends a packet token for this address. Repeat offenders get blocklisted.
+pub async fn allow_packet(ip: IpAddr) -> bool {
+    let now = Instant::now();
+    let should_blocklist = {
+        let mut limits = ADDRESS_LIMITS.lock().await;
+        let address_limits = limits.entry(ip).or_default();
+        address_limits.last_seen = now;
+        if address_limits.packets.try_take(now) {
+            return true;
+        }
+        address_limits.record_violation(now)
+    };
+
+    increment(&SERVER_STATS.packets_rate_limited);
+    if should_blocklist {
+        blocklist(ip).await;
+    }
+    false
+}
+
+/// Spends a connection attempt token for this address, and checks the global pending cap.
+pub async fn allow_connection_attempt(ip: IpAddr) -> bool {
+    let now = Instant::now();
+    let should_blocklist = {
+        let mut limits = ADDRESS_LIMITS.lock().await;
+        let address_limits = limits.entry(ip).or_default();
+        if address_limits.connection_attempts.try_take(now) {
+            None
+        } else {
+            Some(address_limits.record_violation(now))
+        }
+    };
+
+    match should_blocklist {
+        Some(should_blocklist) => {
+            increment(&SERVER_STATS.connection_attempts_rate_limited);
+            if should_blocklist {
+                blocklist(ip).await;
+            }
+            false
+        }
+        None => {
+            if PENDING_CONNECTIONS.read().await.len() >= MAX_PENDING_CONNECTIONS {
+                increment(&SERVER_STATS.pending_connections_rejected);
+                return false;
+            }
+            true
+        }
+    }
+}
+
+/// Garbage counts against an address the same way flooding does.
+pub async fn record_malformed_packet(ip: IpAddr) {
+    let now = Instant::now();
+    let should_blocklist = {
+        let mut limits = ADDRESS_LIMITS.lock().await;
+        limits.entry(ip).or_default().record_violation(now)
+    };
+
+    increment(&SERVER_STATS.malformed_packets);
+    if should_blocklist {
+        blocklist(ip).await;
+    }
+}
+
+////////////////////////    PENDING CONNECTIONS    ////////////////////////
+pub async fn mark_pending(client_id: u32) {
+    PENDING_CONNECTIONS
+        .write()
+        .await
+        .insert(client_id, Instant::now());
+}
+
+/// A client stops being pending the first time it sends anything after its first packet.
+pub async fn confirm_pending(client_id: u32) {
+    let is_pending = PENDING_CONNECTIONS.read().await.contains_key(&client_id);
+    if is_pending {
+        PENDING_CONNECTIONS.write().await.remove(&client_id);
+    }
+}
+
+////////////////////////    HOUSEKEEPING TASK    ////////////////////////
+/// Unblocks addresses whose time is up.
+async fn expire_blocklist(now: Instant) {
+    BLOCKLIST.write().await.retain(|_, until| now < *until);
+}
+
+pub async fn continuously_expire_stale_entries() {
+    let mut interval = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+
+        // drop clients that never followed up on their first packet. the game loop tears
+        // them down when it gets the disconnect
+        let expired: Vec<u32> = {
+            let mut pending_write = PENDING_CONNECTIONS.write().await;
+            let expired: Vec<u32> = pending_write
+                .iter()
+                .filter(|(_, &since)| {
+                    now.saturating_duration_since(since) > PENDING_CONNECTION_TIMEOUT
+                })
+                .map(|(&client_id, _)| client_id)
+                .collect();
+            for client_id in expired.iter() {
+                pending_write.remove(client_id);
+            }
+            expired
+        };
+        for client_id in expired {
+            increment(&SERVER_STATS.pending_connections_expired);
+            let to_self_message = ClientToServerMessageBundle {
+                client_id,
+                message: ClientToServerMessage::Disconnect,
+            };
+            if INCOMING_MESSAGE_QUEUE.push(to_self_message).is_err() {
+                eprintln!(
+                    "Inbound message queue full: dropping disconnect message from {}",
+                    client_id
+                );
+            }
+        }
+
+        expire_blocklist(now).await;
+
+        // forget old violations and idle addresses
+        let mut limits = ADDRESS_LIMITS.lock().await;
+        for address_limits in limits.values_mut() {
+            if let Some(last_violation) = address_limits.last_violation {
+                if now.saturating_duration_since(last_violation) > VIOLATION_FORGET_AFTER {
+                    address_limits.violations = 0;
+                    address_limits.last_violation = None;
+                }
+            }
+        }
+        limits.retain(|_, address_limits| {
+            now.saturating_duration_since(address_limits.last_seen) < VIOLATION_FORGET_AFTER
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_allows_a_burst_then_refuses() {
+        let mut bucket = TokenBucket::new(4.0, 2.0);
+        let now = Instant::now();
+        for _ in 0..4 {
+            assert!(bucket.try_take(now));
+        }
+        assert!(!bucket.try_take(now));
+    }
+
+    #[test]
+    fn token_bucket_refills_at_its_rate() {
+        let mut bucket = TokenBucket::new(4.0, 2.0);
+        let now = Instant::now();
+        while bucket.try_take(now) {}
+
+        // half a second at 2 a second is one token
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn token_bucket_never_holds_more_than_its_capacity() {
+        let mut bucket = TokenBucket::new(4.0, 2.0);
+        let now = Instant::now();
+        bucket.try_take(now);
+
+        let much_later = now + Duration::from_secs(60);
+        for _ in 0..4 {
+            assert!(bucket.try_take(much_later));
+        }
+        assert!(!bucket.try_take(much_later));
+    }
+
+    #[test]
+    fn violations_blocklist_at_the_threshold() {
+        let mut address_limits = AddressLimits::new();
+        let now = Instant::now();
+        for _ in 1..BLOCKLIST_VIOLATION_THRESHOLD {
+            assert!(!address_limits.record_violation(now));
+        }
+        assert!(address_limits.record_violation(now));
+        assert_eq!(address_limits.violations, 0);
+    }
+
+    #[tokio::test]
+    async fn blocklist_expires_after_its_duration() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        blocklist(ip).await;
+        let now = Instant::now();
+        assert!(is_blocklisted_at(ip, now).await);
+
+        let after = now + BLOCKLIST_DURATION + Duration::from_secs(1);
+        assert!(!is_blocklisted_at(ip, after).await);
+        expire_blocklist(now).await;
+        assert!(BLOCKLIST.read().await.contains_key(&ip));
+        expire_blocklist(after).await;
+        assert!(!BLOCKLIST.read().await.contains_key(&ip));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+
+use hecs::{Entity, World};
+
+use crate::{
+    bitpack::{BitPack, BitReader, BitWriter, Quantization, Vec2Quantization},
+    components::{CTransform, OwnerClient, Physics, ProcessedInput, Shape},
+    settings::{ROTATION_PRECISION, SHAPE_MAX, SHAPE_PRECISION},
+    snapshot::{POSITION_QUANTIZATION, VELOCITY_QUANTIZATION},
+};
+
+const ROTATION_QUANTIZATION: Vec2Quantization = Vec2Quantization {
+    x: Quantization::new(-1.0, 1.0, ROTATION_PRECISION),
+    y: Quantization::new(-1.0, 1.0, ROTATION_PRECISION),
+};
+const SHAPE_QUANTIZATION: Vec2Quantization = Vec2Quantization {
+    x: Quantization::new(0.0, SHAPE_MAX, SHAPE_PRECISION),
+    y: Quantization::new(0.0, SHAPE_MAX, SHAPE_PRECISION),
+};
+
+/// A component that is sent to clients. Only the macro below implements it.
+pub trait Replicated: BitPack + Copy {
+    const COMPONENT_ID: u32;
+
+    fn into_data(self) -> ComponentData;
+    fn from_data(data: &ComponentData) -> Option<Self>;
+}
+
+/// Marks components as replicated, each with its id on the wire. Like message ids,
+/// component ids are never reused or renumbered. A component listed here also needs
+/// a `BitPack` impl, which is its layout on the wire.
+macro_rules! replicated_components {
+    ($( $id:literal => $component:ident ),* $(,)?) => {
+        /// One replicated component of an entity.
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum ComponentData {
+            $( $component($component) ),*
+        }
+
+        impl ComponentData {
+            /// Every replicated component with its wire id, in declaration order.
+            pub const COMPONENT_IDS: &'static [(&'static str, u32)] =
+                &[$( (stringify!($component), $id) ),*];
+
+            pub fn component_id(&self) -> u32 {
+                match self {
+                    $( ComponentData::$component(_) => $id ),*
+                }
+            }
+        }
+
+        impl BitPack for ComponentData {
+            fn pack(&self, writer: &mut BitWriter) {
+                writer.write_varint(self.component_id());
+                match self {
+                    $( ComponentData::$component(component) => component.pack(writer) ),*
+                }
+            }
+
+            fn unpack(reader: &mut BitReader) -> Option<Self> {
+                match reader.read_varint()? {
+                    $( $id => Some(ComponentData::$component($component::unpack(reader)?)), )*
+                    _ => None,
+                }
+            }
+        }
+
+        /// The replicated components `entity` has in `world`.
+        pub fn replicated_state(world: &World, entity: Entity) -> EntityState {
+            let mut state = EntityState::new();
+            $(
+                if let Ok(component) = world.get::<&$component>(entity) {
+                    state.insert(*component);
+                }
+            )*
+            state
+        }
+
+        /// Makes the replicated components of `entity` match `state`, adding, replacing
+        /// and removing as needed. Components that are not replicated are left alone.
+        pub fn apply_state(world: &mut World, entity: Entity, state: &EntityState) {
+            $(
+                match state.get::<$component>() {
+                    Some(component) => {
+                        let _ = world.insert_one(entity, component);
+                    }
+                    None => {
+                        let _ = world.remove_one::<$component>(entity);
+                    }
+                }
+            )*
+        }
+
+        $(
+            impl Replicated for $component {
+                const COMPONENT_ID: u32 = $id;
+
+                fn into_data(self) -> ComponentData {
+                    ComponentData::$component(self)
+                }
+
+                fn from_data(data: &ComponentData) -> Option<Self> {
+                    match data {
+                        ComponentData::$component(component) => Some(*component),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+replicated_components! {
+    0 => CTransform,
+    1 => Physics,
+    2 => Shape,
+    3 => OwnerClient,
+    4 => ProcessedInput,
+}
+
+////////////////////////    ENTITY STATE    ////////////////////////
+/// The replicated components of one entity, at most one of each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityState {
+    components: BTreeMap<u32, ComponentData>,
+}
+
+impl EntityState {
+    pub fn new() -> Self {
+        Self {
+            components: BTreeMap::new(),
+        }
+    }
+
+    pub fn with<C: Replicated>(mut self, component: C) -> Self {
+        self.insert(component);
+        self
+    }
+
+    pub fn insert<C: Replicated>(&mut self, component: C) {
+        self.insert_data(component.into_data());
+    }
+
+    pub fn insert_data(&mut self, data: ComponentData) {
+        self.components.insert(data.component_id(), data);
+    }
+
+    pub fn remove(&mut self, component_id: u32) {
+        self.components.remove(&component_id);
+    }
+
+    pub fn get<C: Replicated>(&self) -> Option<C> {
+        C::from_data(self.components.get(&C::COMPONENT_ID)?)
+    }
+
+    pub fn get_data(&self, component_id: u32) -> Option<&ComponentData> {
+        self.components.get(&component_id)
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = &ComponentData> {
+        self.components.values()
+    }
+}
+
+impl Default for EntityState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+////////////////////////    COMPONENT LAYOUTS    ////////////////////////
+impl BitPack for CTransform {
+    fn pack(&self, writer: &mut BitWriter) {
+        POSITION_QUANTIZATION.write(writer, self.pos);
+        ROTATION_QUANTIZATION.write(writer, self.rot);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(CTransform {
+            pos: POSITION_QUANTIZATION.read(reader)?,
+            rot: ROTATION_QUANTIZATION.read(reader)?,
+        })
+    }
+}
+
+impl BitPack for Physics {
+    fn pack(&self, writer: &mut BitWriter) {
+        VELOCITY_QUANTIZATION.write(writer, self.vel);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(Physics {
+            vel: VELOCITY_QUANTIZATION.read(reader)?,
+        })
+    }
+}
+
+impl BitPack for Shape {
+    fn pack(&self, writer: &mut BitWriter) {
+        SHAPE_QUANTIZATION.write(writer, self.dims);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(Shape {
+            dims: SHAPE_QUANTIZATION.read(reader)?,
+        })
+    }
+}
+
+impl BitPack for OwnerClient {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.client_id);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(OwnerClient {
+            client_id: reader.read_varint()?,
+        })
+    }
+}
+
+impl BitPack for ProcessedInput {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write(self.sequence as u64, 32);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(ProcessedInput {
+            sequence: reader.read(32)? as u32,
+        })
+    }
+}
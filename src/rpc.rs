@@ -0,0 +1,269 @@
+use std::{collections::HashMap, fmt, time::Duration};
+
+use glam::Vec2;
+
+use crate::{
+    bitpack::Packed,
+    codec::MAX_WORLD_UPDATE_BYTES,
+    server_to_client::ServerToClientMessage,
+    snapshot::{apply_update, diff, fit_update, EntityStates, WorldUpdate},
+    wire_enum::wire_enum,
+};
+
+// a call that has had no response by now is sent again, with the same request id
+pub const RPC_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+pub const RPC_MAX_ATTEMPTS: u32 = 4;
+// responses the server remembers per client, so a retry is answered instead of run twice
+pub const RPC_RESPONSE_CACHE_SIZE: usize = 16;
+
+wire_enum! {
+    #[derive(Debug, Clone)]
+    pub enum Request {
+        // 0 to 2 are retired, never reuse them
+        3 => AckSnapshot { tick: u32 },
+        4 => JoinV2,
+    }
+}
+
+wire_enum! {
+    #[derive(Debug, Clone)]
+    pub enum Response {
+        // 0 to 2 are retired, never reuse them
+        3 => AckSnapshot { entity_id: u32, pos: Vec2 },
+        // a full snapshot, with no baseline
+        4 => JoinV2 { world: Packed<WorldUpdate> },
+    }
+}
+
+wire_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RpcError {
+        0 => NoHandler,
+        1 => AlreadySpawned,
+        2 => UnknownSnapshot,
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::NoHandler => write!(f, "server has no handler for that request"),
+            RpcError::AlreadySpawned => write!(f, "already have a player"),
+            RpcError::UnknownSnapshot => write!(f, "that snapshot was never sent"),
+        }
+    }
+}
+
+pub type RpcResult = Result<Response, RpcError>;
+
+////////////////////////    TYPED METHODS    ////////////////////////
+/// One request type and the response it gets back. The wire only ever sees
+/// `Request` and `Response`, this is what lets both ends work with the real types.
+pub trait RpcMethod: Sized + Default {
+    type Response;
+
+    fn into_request(self) -> Request;
+    fn from_request(request: Request) -> Option<Self>;
+    fn into_response(response: Self::Response) -> Response;
+    fn from_response(response: Response) -> Option<Self::Response>;
+
+    /// The id its requests go out with, which picks the handler they are routed to.
+    fn request_id() -> u32 {
+        Self::default().into_request().message_id()
+    }
+}
+
+/// Asks for the world as it is right now. Sending it marks the caller as joined,
+/// so everything that happens after the snapshot reaches them as normal broadcasts.
+#[derive(Default)]
+pub struct Join;
+
+pub struct WorldSnapshot {
+    pub tick: u32,
+    pub entities: EntityStates,
+}
+
+impl RpcMethod for Join {
+    type Response = WorldSnapshot;
+
+    fn into_request(self) -> Request {
+        Request::JoinV2
+    }
+
+    fn from_request(request: Request) -> Option<Self> {
+        match request {
+            Request::JoinV2 => Some(Join),
+            _ => None,
+        }
+    }
+
+    fn into_response(snapshot: WorldSnapshot) -> Response {
+        // a world too big for one packet arrives in part, the snapshots after it
+        // bring the rest
+        let mut world = diff(snapshot.tick, None, &snapshot.entities);
+        fit_update(&mut world, MAX_WORLD_UPDATE_BYTES);
+        Response::JoinV2 {
+            world: Packed(world),
+        }
+    }
+
+    fn from_response(response: Response) -> Option<WorldSnapshot> {
+        match response {
+            Response::JoinV2 { world } => Some(WorldSnapshot {
+                tick: world.0.tick,
+                entities: apply_update(&EntityStates::new(), &world.0)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Confirms the snapshot from `Join` was applied. Only then does the server spawn
+/// the caller's player.
+#[derive(Default)]
+pub struct AckSnapshot {
+    pub tick: u32,
+}
+
+pub struct SpawnedPlayer {
+    pub entity_id: u32,
+    pub pos: Vec2,
+}
+
+impl RpcMethod for AckSnapshot {
+    type Response = SpawnedPlayer;
+
+    fn into_request(self) -> Request {
+        Request::AckSnapshot { tick: self.tick }
+    }
+
+    fn from_request(request: Request) -> Option<Self> {
+        match request {
+            Request::AckSnapshot { tick } => Some(AckSnapshot { tick }),
+            _ => None,
+        }
+    }
+
+    fn into_response(spawned: SpawnedPlayer) -> Response {
+        Response::AckSnapshot {
+            entity_id: spawned.entity_id,
+            pos: spawned.pos,
+        }
+    }
+
+    fn from_response(response: Response) -> Option<SpawnedPlayer> {
+        match response {
+            Response::AckSnapshot { entity_id, pos } => Some(SpawnedPlayer { entity_id, pos }),
+            _ => None,
+        }
+    }
+}
+
+////////////////////////    SERVER HANDLERS    ////////////////////////
+/// What a handler knows about the call besides its arguments.
+pub struct RpcContext {
+    pub client_id: u32,
+    // sent to every other client once the response is queued
+    pub announcements: Vec<ServerToClientMessage>,
+}
+
+impl RpcContext {
+    pub fn new(client_id: u32) -> Self {
+        Self {
+            client_id,
+            announcements: Vec::new(),
+        }
+    }
+}
+
+type Handler<S> = Box<dyn Fn(&mut S, &mut RpcContext, Request) -> RpcResult + Send + Sync>;
+
+/// One handler per request type, run against whatever state `S` the server keeps.
+pub struct RpcHandlers<S> {
+    handlers: HashMap<u32, Handler<S>>,
+}
+
+impl<S: 'static> RpcHandlers<S> {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn register<M: RpcMethod + 'static>(
+        &mut self,
+        handler: fn(&mut S, &mut RpcContext, M) -> Result<M::Response, RpcError>,
+    ) -> &mut Self {
+        let wrapped = move |state: &mut S, context: &mut RpcContext, request| {
+            let request = M::from_request(request).ok_or(RpcError::NoHandler)?;
+            handler(state, context, request).map(M::into_response)
+        };
+        if self
+            .handlers
+            .insert(M::request_id(), Box::new(wrapped))
+            .is_some()
+        {
+            panic!(
+                "two rpc handlers registered for request {}",
+                M::request_id()
+            );
+        }
+        self
+    }
+
+    pub fn handle(&self, state: &mut S, context: &mut RpcContext, request: Request) -> RpcResult {
+        match self.handlers.get(&request.message_id()) {
+            Some(handler) => handler(state, context, request),
+            None => Err(RpcError::NoHandler),
+        }
+    }
+}
+
+impl<S: 'static> Default for RpcHandlers<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join(_: &mut (), _: &mut RpcContext, _: Join) -> Result<WorldSnapshot, RpcError> {
+        Ok(WorldSnapshot {
+            tick: 7,
+            entities: EntityStates::new(),
+        })
+    }
+
+    fn ack_snapshot(
+        _: &mut (),
+        _: &mut RpcContext,
+        ack: AckSnapshot,
+    ) -> Result<SpawnedPlayer, RpcError> {
+        Ok(SpawnedPlayer {
+            entity_id: ack.tick,
+            pos: Vec2::ZERO,
+        })
+    }
+
+    #[test]
+    fn requests_reach_the_handler_of_their_method() {
+        let mut handlers = RpcHandlers::new();
+        handlers.register::<AckSnapshot>(ack_snapshot);
+        let mut context = RpcContext::new(1);
+
+        let request = AckSnapshot { tick: 3 }.into_request();
+        let response = handlers.handle(&mut (), &mut context, request).unwrap();
+        let spawned = AckSnapshot::from_response(response).unwrap();
+        assert_eq!(spawned.entity_id, 3);
+
+        let unregistered = Join.into_request();
+        let result = handlers.handle(&mut (), &mut context, unregistered);
+        assert!(matches!(result, Err(RpcError::NoHandler)));
+
+        handlers.register::<Join>(join);
+        let response = handlers.handle(&mut (), &mut context, Join.into_request());
+        assert_eq!(Join::from_response(response.unwrap()).unwrap().tick, 7);
+    }
+}
@@ -1,54 +1,231 @@
-use std::{collections::HashMap, time::Instant};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 use glam::Vec2;
+use lazy_static::lazy_static;
 
 use crate::{
-    enque_outbound_messages::{broadcast_to_all, broadcast_to_all_except, send_to_one_client},
+    bitpack::Packed,
+    bookkeeping::{
+        client_network_stats, joined_clients, mark_joined, remove_client,
+        report_client_network_stats,
+    },
+    codec::MAX_WORLD_UPDATE_BYTES,
+    enque_outbound_messages::{broadcast_to_all_except, send_to_one_client},
+    rpc::{
+        AckSnapshot, Join, Request, RpcContext, RpcError, RpcHandlers, RpcResult, SpawnedPlayer,
+        WorldSnapshot, RPC_RESPONSE_CACHE_SIZE,
+    },
+    server_stats,
+    settings::{
+        KICK_AFTER_VIOLATIONS, SNAPSHOTS_PER_SECOND, TICKS_PER_SECOND, WORLD_MAX, WORLD_MIN,
+    },
+    snapshot::{apply_update, diff, fit_update, tick_is_newer, EntityStates},
+    systems::{Stage, Systems},
+    validation::{check_input, Violation},
     {
         client_to_server::{ClientToServerMessage, ClientToServerMessageBundle},
-        game_objects::Player,
-        server_to_client::ServerToClientMessage,
+        components::{CTransform, InputQueue, NetworkId, OwnerClient, Physics, ProcessedInput},
+        game_objects::player_bundle,
+        replication::replicated_state,
+        server_to_client::{RejectionReason, ServerToClientMessage},
     },
 };
 
-use super::{server_state::ServerState, server_udp_networking::INCOMING_MESSAGE_QUEUE};
+use super::{
+    server_state::ServerState, server_udp_networking::INCOMING_MESSAGE_QUEUE,
+    shutdown::is_shutting_down,
+};
 
-pub const FRAMES_PER_SECOND: u32 = 60;
-const TIMESTEP: f32 = 1.0 / FRAMES_PER_SECOND as f32;
+pub const SNAPSHOT_INTERVAL_TICKS: u64 = (TICKS_PER_SECOND / SNAPSHOTS_PER_SECOND) as u64;
 
 pub const DEBUG_PRINT_PROCESSED_MESSAGES: bool = false;
 
+// long enough for the kick notice to leave before the client's mailbox is gone
+const KICK_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+lazy_static! {
+    static ref RPC_HANDLERS: RpcHandlers<ServerState> = rpc_handlers();
+    static ref SYSTEMS: Systems<ServerState> = systems();
+}
+
+fn rpc_handlers() -> RpcHandlers<ServerState> {
+    let mut handlers = RpcHandlers::new();
+    handlers
+        .register::<Join>(join)
+        .register::<AckSnapshot>(ack_snapshot);
+    handlers
+}
+
+/// What happens each tick. Add new game logic here, in the stage it belongs to.
+fn systems() -> Systems<ServerState> {
+    let mut systems = Systems::new();
+    systems
+        .add(Stage::Input, "input", input_system)
+        .add(Stage::Simulation, "movement", movement_system)
+        .add(Stage::GameRules, "world_bounds", world_bounds_system)
+        .add(
+            Stage::Replication,
+            "transform_history",
+            transform_history_system,
+        )
+        .add(Stage::Replication, "snapshot", snapshot_system)
+        .add(Stage::Cleanup, "departed_clients", departed_clients_system);
+    systems
+}
+
 pub async fn main_loop(state: &mut ServerState) {
-    let mut previous_time = Instant::now();
+    state.scheduler.start();
     loop {
-        process_message_queue(state).await;
-
-        let current_time = Instant::now();
-        let dt = (current_time - previous_time).as_secs_f32();
-        previous_time = current_time;
+        if is_shutting_down() {
+            return;
+        }
 
-        state.time_since_last_update += dt;
-        while state.time_since_last_update > TIMESTEP {
-            state.time_since_last_update -= TIMESTEP;
+        state.scheduler.wait().await;
+        process_message_queue(state).await;
 
+        for _ in 0..state.scheduler.due_ticks() {
+            let started = Instant::now();
             step(state);
+            state.scheduler.record_tick_time(started.elapsed());
             // state.print_state();
         }
+
+        send_outbox(state).await;
+        if state.scheduler.report_if_due() {
+            SYSTEMS.report_times();
+            server_stats::report();
+            report_client_network_stats().await;
+        }
     }
 }
 
 pub fn step(state: &mut ServerState) {
-    for (_, player) in state.players.iter_mut() {
-        player.step();
-    }
+    state.scheduler.advance();
+    SYSTEMS.run(state);
     // state.print_state();
 }
 
+/// Turns each player's input for this tick into a velocity. Clients never move their
+/// own players, they only say what they are pressing.
+fn input_system(state: &mut ServerState) {
+    for (_, (inputs, physics, processed)) in
+        state
+            .world
+            .query_mut::<(&mut InputQueue, &mut Physics, &mut ProcessedInput)>()
+    {
+        let command = inputs.next();
+        physics.vel = command.velocity();
+        processed.sequence = command.sequence;
+    }
+}
+
+fn movement_system(state: &mut ServerState) {
+    for (_, (transform, physics)) in state.world.query_mut::<(&mut CTransform, &Physics)>() {
+        transform.pos += physics.vel;
+    }
+}
+
+/// Nothing leaves the world. Clients keep their own player in it the same way when they
+/// predict it.
+fn world_bounds_system(state: &mut ServerState) {
+    for (_, transform) in state.world.query_mut::<&mut CTransform>() {
+        transform.pos = transform.pos.clamp(WORLD_MIN, WORLD_MAX);
+    }
+}
+
+/// Remembers where everything ended up, for judging hits as lagging clients saw them.
+fn transform_history_system(state: &mut ServerState) {
+    let tick = state.scheduler.tick();
+    state.transform_history.record(tick, &state.world);
+}
+
+/// The entity state every joined client should treat as the truth, stamped with the
+/// tick it was taken at. Events like chat and joins still go out on their own.
+/// Each client gets the spawns, updates and despawns since the newest snapshot it has
+/// acknowledged, or every entity as a spawn if that one has fallen out of the history.
+/// What does not fit in one packet waits for the next snapshot.
+fn snapshot_system(state: &mut ServerState) {
+    let now = state.scheduler.tick();
+    if now - state.last_snapshot_tick < SNAPSHOT_INTERVAL_TICKS {
+        return;
+    }
+    state.last_snapshot_tick = now;
+    // snapshots carry the low 32 bits, which take over two years at 60 ticks a second
+    // to wrap. acks are compared with `tick_is_newer` and clients `unwrap_tick` them,
+    // so it does no harm when they do
+    let tick = now as u32;
+    let entities = replicated_entities(state);
+    let empty = EntityStates::new();
+
+    for client_id in joined_clients() {
+        let history = state.snapshot_history.entry(client_id).or_default();
+        let baseline = state
+            .snapshot_acks
+            .get(&client_id)
+            .and_then(|&baseline_tick| Some((baseline_tick, history.get(baseline_tick)?)));
+        let mut update = diff(tick, baseline, &entities);
+        fit_update(&mut update, MAX_WORLD_UPDATE_BYTES);
+        // what the client has once it applies this, which is all of `entities` unless
+        // some did not fit
+        let baseline = baseline.map_or(&empty, |(_, baseline)| baseline);
+        let sent = apply_update(baseline, &update).expect("update was made against this baseline");
+        history.push(tick, sent);
+
+        let outbound_message = ServerToClientMessage::Replication {
+            update: Packed(update),
+        };
+        state.outbox.push((client_id, outbound_message));
+    }
+}
+
+/// Whatever departed clients owned goes with them, once the tick is over. Everyone else
+/// sees it go in the next snapshot.
+fn departed_clients_system(state: &mut ServerState) {
+    for client_id in std::mem::take(&mut state.departed_clients) {
+        let owned: Vec<u32> = state
+            .world
+            .query::<(&NetworkId, &OwnerClient)>()
+            .iter()
+            .filter(|(_, (_, owner))| owner.client_id == client_id)
+            .map(|(_, (network_id, _))| network_id.entity_id)
+            .collect();
+
+        for entity_id in owned {
+            state.registry.despawn(&mut state.world, entity_id);
+            println!("despawned entity {}", entity_id);
+        }
+    }
+}
+
+/// Sends what systems queued during the ticks just run, in the order they queued it.
+pub async fn send_outbox(state: &mut ServerState) {
+    for (client_id, outbound_message) in state.outbox.drain(..) {
+        send_to_one_client(client_id, outbound_message).await;
+    }
+}
+
+/// Every entity as clients see it, made of its replicated components.
+fn replicated_entities(state: &ServerState) -> EntityStates {
+    state
+        .world
+        .query::<&NetworkId>()
+        .iter()
+        .map(|(entity, network_id)| (network_id.entity_id, replicated_state(&state.world, entity)))
+        .collect()
+}
+
 pub async fn process_message_queue(state: &mut ServerState) {
     // prune_latest_only_messages().await;
+    disconnect_kicked_clients(state).await;
 
     while let Some(message_bundle) = INCOMING_MESSAGE_QUEUE.pop() {
         let client_id = message_bundle.client_id;
+        if is_kicked(state, client_id) {
+            continue;
+        }
         match message_bundle.message {
             ClientToServerMessage::Connect => {
                 println!("Client {} connected", client_id);
@@ -65,10 +242,7 @@ pub async fn process_message_queue(state: &mut ServerState) {
             }
             ClientToServerMessage::Disconnect => {
                 println!("Client {} disconnected", client_id);
-
-                // announce the leave
-                let outbound_message = ServerToClientMessage::ClientLeft { id: client_id };
-                broadcast_to_all_except(client_id, outbound_message).await;
+                disconnect_client(state, client_id).await;
             }
             ClientToServerMessage::ChatMessage { message } => {
                 println!("{} says: {}", client_id, message);
@@ -80,45 +254,222 @@ pub async fn process_message_queue(state: &mut ServerState) {
                 };
                 broadcast_to_all_except(client_id, outbound_message).await;
             }
-            ClientToServerMessage::RequestToSpawnPlayer => {
-                println!("{} requested to spawn a player", client_id);
-
-                let eid = state.next_eid;
-                state.next_eid += 1;
-
-                // spawn the player
-                let player = Player::new(client_id, eid);
-                state.players.insert(eid, player);
-                println!("spawned player {}", eid);
-
-                // announce the spawn
-                let outbound_message = ServerToClientMessage::SpawnPlayer {
-                    owner_client_id: client_id,
-                    entity_id: eid,
-                    pos: Vec2::ZERO,
-                };
-                broadcast_to_all(outbound_message).await;
+            ClientToServerMessage::SnapshotAck { tick } => {
+                // only ticks we actually sent, and never backwards
+                let history = state.snapshot_history.get(&client_id);
+                if history.is_some_and(|history| history.get(tick).is_some()) {
+                    let acked = state.snapshot_acks.entry(client_id).or_insert(tick);
+                    if tick_is_newer(tick, *acked) {
+                        *acked = tick;
+                    }
+                }
+            }
+            ClientToServerMessage::Request {
+                request_id,
+                request,
+            } => {
+                handle_request(state, client_id, request_id, request).await;
             }
-            ClientToServerMessage::EntityPosition { entity_id, pos } => {
-                if let Some(player) = state.players.get_mut(&entity_id) {
-                    player.pos = pos;
+            ClientToServerMessage::Input {
+                command: Packed(command),
+            } => {
+                if let Err(violation) = check_input(&state.world, client_id) {
+                    report_violation(state, client_id, violation).await;
+                    continue;
+                }
+                // simulated on the next ticks, and everyone sees the result in snapshots
+                for (_, (owner, inputs)) in
+                    state.world.query_mut::<(&OwnerClient, &mut InputQueue)>()
+                {
+                    if owner.client_id == client_id {
+                        inputs.push(command);
+                    }
                 }
-
-                let outbound_message = ServerToClientMessage::EntityPosition { entity_id, pos };
-                broadcast_to_all_except(client_id, outbound_message).await;
             }
-            ClientToServerMessage::RequestAllPlayers => {
-                println!("{} requested all players", client_id);
-
-                let players = state.players.values().cloned().collect();
-
-                let outbound_message = ServerToClientMessage::AllPlayers { players };
-                send_to_one_client(client_id, outbound_message).await;
+            ClientToServerMessage::ConnectRequest { .. }
+            | ClientToServerMessage::Ping { .. }
+            | ClientToServerMessage::Pong { .. } => {
+                // handled by the networking layer, never queued for the game
             }
         }
     }
 }
 
+/// Forgets a client that left, was kicked or went quiet, frees its connection, and
+/// tells everyone else. Every way out of the game ends here.
+async fn disconnect_client(state: &mut ServerState, client_id: u32) {
+    state.rpc_responses.remove(&client_id);
+    state.pending_joins.remove(&client_id);
+    state.snapshot_acks.remove(&client_id);
+    state.snapshot_history.remove(&client_id);
+    state.violations.remove(&client_id);
+    state.kicked.remove(&client_id);
+    state.departed_clients.push(client_id);
+    remove_client(client_id).await;
+
+    // announce the leave
+    let outbound_message = ServerToClientMessage::ClientLeft { id: client_id };
+    broadcast_to_all_except(client_id, outbound_message).await;
+}
+
+////////////////////////    LAG COMPENSATION    ////////////////////////
+/// Entities at `point` as `client_id` saw the world when it acted, rewound by its
+/// round trip and interpolation delay. Anything that decides whether a client hit
+/// something should ask this rather than the current world.
+pub async fn hit_test_as_seen_by(state: &ServerState, client_id: u32, point: Vec2) -> Vec<u32> {
+    let rtt = match client_network_stats(client_id).await {
+        Some(stats) => stats.rtt(),
+        None => None,
+    };
+    state
+        .transform_history
+        .hit_test_as_seen(state.scheduler.tick(), rtt, TICKS_PER_SECOND, point)
+}
+
+////////////////////////    VIOLATIONS    ////////////////////////
+fn is_kicked(state: &ServerState, client_id: u32) -> bool {
+    state.kicked.contains_key(&client_id)
+}
+
+/// Logs a refused message against the client, and kicks it once it has had
+/// `KICK_AFTER_VIOLATIONS` of them.
+async fn report_violation(state: &mut ServerState, client_id: u32, violation: Violation) {
+    let count = state.violations.entry(client_id).or_insert(0);
+    *count += 1;
+    eprintln!(
+        "Refused message from client {} ({} of {}): {}",
+        client_id, count, KICK_AFTER_VIOLATIONS, violation
+    );
+    if *count >= KICK_AFTER_VIOLATIONS && !is_kicked(state, client_id) {
+        kick(state, client_id).await;
+    }
+}
+
+/// Tells the client why, and disconnects it once that has had time to go out. Until
+/// then everything else it sends is ignored, its own `Disconnect` included.
+async fn kick(state: &mut ServerState, client_id: u32) {
+    println!("Kicking client {}", client_id);
+    let outbound_message = ServerToClientMessage::ConnectionRejected {
+        reason: RejectionReason::Kicked,
+    };
+    send_to_one_client(client_id, outbound_message).await;
+    state
+        .kicked
+        .insert(client_id, Instant::now() + KICK_GRACE_PERIOD);
+}
+
+async fn disconnect_kicked_clients(state: &mut ServerState) {
+    let now = Instant::now();
+    let due: Vec<u32> = state
+        .kicked
+        .iter()
+        .filter(|(_, &until)| now >= until)
+        .map(|(&client_id, _)| client_id)
+        .collect();
+    for client_id in due {
+        println!("Client {} kicked", client_id);
+        disconnect_client(state, client_id).await;
+    }
+}
+
+////////////////////////    RPC    ////////////////////////
+/// Runs the handler for `request` and answers with its result. A retry of a request
+/// we already answered gets the same answer again without running the handler twice.
+async fn handle_request(
+    state: &mut ServerState,
+    client_id: u32,
+    request_id: u32,
+    request: Request,
+) {
+    let cached = state
+        .rpc_responses
+        .get(&client_id)
+        .and_then(|responses| responses.iter().find(|(id, _)| *id == request_id))
+        .map(|(_, result)| result.clone());
+    if let Some(result) = cached {
+        let outbound_message = ServerToClientMessage::Response { request_id, result };
+        send_to_one_client(client_id, outbound_message).await;
+        return;
+    }
+
+    let mut context = RpcContext::new(client_id);
+    let result = RPC_HANDLERS.handle(state, &mut context, request);
+    if let Err(e) = &result {
+        println!("request {} from {} failed: {}", request_id, client_id, e);
+    }
+    remember_response(state, client_id, request_id, result.clone());
+
+    let outbound_message = ServerToClientMessage::Response { request_id, result };
+    send_to_one_client(client_id, outbound_message).await;
+    for announcement in context.announcements {
+        broadcast_to_all_except(client_id, announcement).await;
+    }
+}
+
+fn remember_response(state: &mut ServerState, client_id: u32, request_id: u32, result: RpcResult) {
+    let responses = state.rpc_responses.entry(client_id).or_default();
+    if responses.len() == RPC_RESPONSE_CACHE_SIZE {
+        responses.pop_front();
+    }
+    responses.push_back((request_id, result));
+}
+
+fn join(
+    state: &mut ServerState,
+    context: &mut RpcContext,
+    _: Join,
+) -> Result<WorldSnapshot, RpcError> {
+    let client_id = context.client_id;
+    let tick = state.scheduler.tick() as u32;
+    println!("{} asked to join at tick {}", client_id, tick);
+
+    // from here on broadcasts reach this client too, and they all come after the
+    // snapshot in its mailbox
+    mark_joined(client_id);
+    state.pending_joins.insert(client_id, tick);
+
+    Ok(WorldSnapshot {
+        tick,
+        entities: replicated_entities(state),
+    })
+}
+
+fn ack_snapshot(
+    state: &mut ServerState,
+    context: &mut RpcContext,
+    ack: AckSnapshot,
+) -> Result<SpawnedPlayer, RpcError> {
+    let client_id = context.client_id;
+    if state
+        .world
+        .query::<&OwnerClient>()
+        .iter()
+        .any(|(_, owner)| owner.client_id == client_id)
+    {
+        return Err(RpcError::AlreadySpawned);
+    }
+    if state.pending_joins.get(&client_id) != Some(&ack.tick) {
+        return Err(RpcError::UnknownSnapshot);
+    }
+    state.pending_joins.remove(&client_id);
+
+    // spawn the player
+    let pos = Vec2::ZERO;
+    let eid = state
+        .registry
+        .spawn(&mut state.world, player_bundle(client_id, pos));
+    if let Some(entity) = state.registry.entity(eid) {
+        let controls = (InputQueue::default(), ProcessedInput { sequence: 0 });
+        let _ = state.world.insert(entity, controls);
+    }
+    println!("spawned player {} for {}", eid, client_id);
+
+    Ok(SpawnedPlayer {
+        entity_id: eid,
+        pos,
+    })
+}
+
 pub async fn prune_latest_only_messages() {
     let queue = INCOMING_MESSAGE_QUEUE.clone();
 
@@ -145,9 +496,44 @@ pub async fn prune_latest_only_messages() {
     }
 }
 
-pub fn get_keep_latest_only_message_type_id(message: &ClientToServerMessage) -> Option<u8> {
-    match message {
-        ClientToServerMessage::EntityPosition { .. } => Some(0),
-        _ => None,
+pub fn get_keep_latest_only_message_type_id(_message: &ClientToServerMessage) -> Option<u8> {
+    // every input counts, and nothing else a client sends replaces the one before it
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::Shape;
+
+    #[tokio::test]
+    async fn hits_are_judged_where_the_client_saw_things() {
+        let mut state = ServerState::new();
+        let entity_id = state.registry.spawn(
+            &mut state.world,
+            (
+                CTransform {
+                    pos: Vec2::new(10.0, 50.0),
+                    rot: Vec2::ZERO,
+                },
+                Physics {
+                    vel: Vec2::new(1.0, 0.0),
+                },
+                Shape {
+                    dims: Vec2::new(2.0, 2.0),
+                },
+            ),
+        );
+        for _ in 0..20 {
+            step(&mut state);
+        }
+
+        // a client we know no round trip for is only rewound by the interpolation
+        // delay, to tick 14, when the entity had moved 14 of its 20
+        let client_id = u32::MAX;
+        let seen = hit_test_as_seen_by(&state, client_id, Vec2::new(24.0, 50.0)).await;
+        assert_eq!(seen, vec![entity_id]);
+        let now = hit_test_as_seen_by(&state, client_id, Vec2::new(30.0, 50.0)).await;
+        assert!(now.is_empty());
     }
 }
@@ -1,28 +1,90 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    sync::Arc,
+    time::Instant,
+};
 
+use glam::Vec2;
+use hecs::World;
 use tokio::{net::TcpStream, sync::Mutex};
 
-use crate::game_objects::Player;
+use crate::{
+    components::{CTransform, NetworkId, OwnerClient, Physics, Registry},
+    game_objects::Player,
+    lag_compensation::TransformHistory,
+    rpc::RpcResult,
+    server_to_client::ServerToClientMessage,
+    settings::TICKS_PER_SECOND,
+    snapshot::SnapshotHistory,
+    tick_scheduler::TickScheduler,
+};
 
 pub struct ServerState {
-    pub time_since_last_update: f32,
-    pub next_id: u32,
-    pub next_eid: u32,
-    pub players: HashMap<u32, Player>,
+    // owns the tick number and when the next one is due
+    pub scheduler: TickScheduler,
+    pub last_snapshot_tick: u64,
+    // what each client was sent at each recent snapshot tick, to diff the next one
+    // against. kept per client because not everyone gets all of a big snapshot
+    pub snapshot_history: HashMap<u32, SnapshotHistory>,
+    // newest snapshot tick each client has acknowledged
+    pub snapshot_acks: HashMap<u32, u32>,
+    // messages systems queued for clients, sent once the ticks being run are over
+    pub outbox: Vec<(u32, ServerToClientMessage)>,
+    pub world: World,
+    pub registry: Registry,
+    // where every entity was over the last few ticks, for judging hits as clients saw them
+    pub transform_history: TransformHistory,
     pub clients: HashMap<u32, Arc<Mutex<TcpStream>>>,
+    // latest answered request ids per client, oldest first
+    pub rpc_responses: HashMap<u32, VecDeque<(u32, RpcResult)>>,
+    // clients that have been sent a snapshot and not acked it yet, with its tick
+    pub pending_joins: HashMap<u32, u32>,
+    // refused messages per client
+    pub violations: HashMap<u32, u32>,
+    // kicked clients, and when they get disconnected. until then what they send is ignored
+    pub kicked: HashMap<u32, Instant>,
+    // clients that left since the last tick, whose entities go in its cleanup stage
+    pub departed_clients: Vec<u32>,
 }
 
 impl ServerState {
     pub fn new() -> Self {
         Self {
-            time_since_last_update: 0.0,
-            next_id: 0,
-            next_eid: 0,
-            players: HashMap::new(),
+            scheduler: TickScheduler::new(TICKS_PER_SECOND),
+            last_snapshot_tick: 0,
+            snapshot_history: HashMap::new(),
+            snapshot_acks: HashMap::new(),
+            outbox: Vec::new(),
+            world: World::new(),
+            registry: Registry::new(),
+            transform_history: TransformHistory::new(),
             clients: HashMap::new(),
+            rpc_responses: HashMap::new(),
+            pending_joins: HashMap::new(),
+            violations: HashMap::new(),
+            kicked: HashMap::new(),
+            departed_clients: Vec::new(),
         }
     }
 
+    /// Saved as a list of players, the same as before the world held them.
+    pub fn save_to_file(&self, path: &str) -> io::Result<()> {
+        let players: Vec<Player> = self
+            .world
+            .query::<(&NetworkId, &OwnerClient, &CTransform, Option<&Physics>)>()
+            .iter()
+            .map(|(_, (network_id, owner, transform, physics))| Player {
+                owner_client_id: owner.client_id,
+                entity_id: network_id.entity_id,
+                pos: transform.pos,
+                vel: physics.map_or(Vec2::ZERO, |physics| physics.vel),
+            })
+            .collect();
+        let bytes = bincode::serialize(&players).map_err(io::Error::other)?;
+        fs::write(path, bytes)
+    }
+
     // pub fn print_state(&self) {
     //     for (id, player) in self.players.iter() {
     //         println!("Player {}: pos: {}, vel: {}", id, player.pos, player.vel);
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref SERVER_STATS: ServerStats = ServerStats::new();
+}
+
+////////////////////////    SERVER WIDE COUNTERS    ////////////////////////
+pub struct ServerStats {
+    pub packets_rate_limited: AtomicU64,
+    pub packets_from_blocklisted: AtomicU64,
+    pub connection_attempts_rate_limited: AtomicU64,
+    pub pending_connections_rejected: AtomicU64,
+    pub pending_connections_expired: AtomicU64,
+    pub addresses_blocklisted: AtomicU64,
+    pub connections_rejected: AtomicU64,
+    pub malformed_packets: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerStatsSnapshot {
+    pub packets_rate_limited: u64,
+    pub packets_from_blocklisted: u64,
+    pub connection_attempts_rate_limited: u64,
+    pub pending_connections_rejected: u64,
+    pub pending_connections_expired: u64,
+    pub addresses_blocklisted: u64,
+    pub connections_rejected: u64,
+    pub malformed_packets: u64,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self {
+            packets_rate_limited: AtomicU64::new(0),
+            packets_from_blocklisted: AtomicU64::new(0),
+            connection_attempts_rate_limited: AtomicU64::new(0),
+            pending_connections_rejected: AtomicU64::new(0),
+            pending_connections_expired: AtomicU64::new(0),
+            addresses_blocklisted: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+            malformed_packets: AtomicU64::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> ServerStatsSnapshot {
+        ServerStatsSnapshot {
+            packets_rate_limited: self.packets_rate_limited.load(Ordering::Relaxed),
+            packets_from_blocklisted: self.packets_from_blocklisted.load(Ordering::Relaxed),
+            connection_attempts_rate_limited: self
+                .connection_attempts_rate_limited
+                .load(Ordering::Relaxed),
+            pending_connections_rejected: self.pending_connections_rejected.load(Ordering::Relaxed),
+            pending_connections_expired: self.pending_connections_expired.load(Ordering::Relaxed),
+            addresses_blocklisted: self.addresses_blocklisted.load(Ordering::Relaxed),
+            connections_rejected: self.connections_rejected.load(Ordering::Relaxed),
+            malformed_packets: self.malformed_packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Prints every counter so far, for the server's periodic report.
+pub fn report() {
+    let stats = SERVER_STATS.snapshot();
+    println!(
+        "  packets: {} rate limited, {} from blocklisted addresses, {} malformed",
+        stats.packets_rate_limited, stats.packets_from_blocklisted, stats.malformed_packets
+    );
+    println!(
+        "  connections: {} rate limited, {} rejected, {} addresses blocklisted",
+        stats.connection_attempts_rate_limited,
+        stats.connections_rejected,
+        stats.addresses_blocklisted
+    );
+    println!(
+        "  pending connections: {} turned away, {} expired",
+        stats.pending_connections_rejected, stats.pending_connections_expired
+    );
+}
+
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
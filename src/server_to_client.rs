@@ -1,36 +1,51 @@
-use glam::Vec2;
-use serde::{Deserialize, Serialize};
+use std::fmt;
 
-use crate::game_objects::Player;
+use crate::{bitpack::Packed, rpc::RpcResult, snapshot::WorldUpdate, wire_enum::wire_enum};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ServerToClientMessage {
-    ClientIDAssignment {
-        new_client_id: u32,
-    },
-    Welcome {
-        server_message: String,
-    },
-    ClientJoined {
-        id: u32,
-    },
-    ClientLeft {
-        id: u32,
-    },
-    ChatMessage {
-        from: u32,
-        message: String,
-    },
-    SpawnPlayer {
-        owner_client_id: u32,
-        entity_id: u32,
-        pos: Vec2,
-    },
-    EntityPosition {
-        entity_id: u32,
-        pos: Vec2,
-    },
-    AllPlayers {
-        players: Vec<Player>,
-    },
+wire_enum! {
+    #[derive(Debug, Clone)]
+    pub enum ServerToClientMessage {
+        0 => ClientIDAssignment { new_client_id: u32 },
+        1 => Welcome { server_message: String },
+        2 => ClientJoined { id: u32 },
+        3 => ClientLeft { id: u32 },
+        4 => ChatMessage { from: u32, message: String },
+        // 5 to 7 are retired, never reuse them
+        8 => ConnectionRejected { reason: RejectionReason },
+        9 => ServerShutdown { reason: String },
+        10 => Ping { sequence: u32 },
+        11 => Pong { sequence: u32 },
+        12 => Response { request_id: u32, result: RpcResult },
+        // 13 to 17 are retired, never reuse them
+        18 => Replication { update: Packed<WorldUpdate> },
+    }
+}
+
+wire_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RejectionReason {
+        0 => ServerFull,
+        1 => Banned,
+        2 => VersionMismatch { server_version: u32 },
+        3 => ShuttingDown,
+        4 => Kicked,
+    }
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectionReason::ServerFull => write!(f, "server is full"),
+            RejectionReason::Banned => write!(f, "you are banned from this server"),
+            RejectionReason::VersionMismatch { server_version } => {
+                write!(
+                    f,
+                    "version mismatch (server speaks protocol {})",
+                    server_version
+                )
+            }
+            RejectionReason::ShuttingDown => write!(f, "server is shutting down"),
+            RejectionReason::Kicked => write!(f, "kicked for breaking the rules"),
+        }
+    }
 }
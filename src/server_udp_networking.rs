@@ -6,6 +6,7 @@ use std::{
 
 use crossbeam::queue::ArrayQueue;
 use lazy_static::lazy_static;
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
     io::{self},
     net::UdpSocket,
@@ -13,74 +14,326 @@ use tokio::{
 };
 
 use crate::{
-    bookkeeping::{add_client, SOCKET_ADDRESS_TO_CLIENT_ID},
+    bookkeeping::{
+        add_client, canonical_socket_address, client_network_stats, CLIENT_NETWORK_STATS,
+        SOCKET_ADDRESS_TO_CLIENT_ID,
+    },
     client_to_server::{ClientToServerMessage, ClientToServerMessageBundle},
+    codec::{decode, encode, DecodeError, MAX_PACKET_SIZE},
+    network_stats::{ConnectionStats, DropReason, PING_INTERVAL},
+    rate_limiting::{
+        allow_connection_attempt, allow_packet, confirm_pending, continuously_expire_stale_entries,
+        is_blocklisted, mark_pending, record_malformed_packet,
+    },
+    server_stats::{increment, SERVER_STATS},
+    server_to_client::{RejectionReason, ServerToClientMessage},
 };
 use crate::{
-    bookkeeping::{CLIENT_ID_TO_SOCKET_ADDRESS, CLIENT_OUTBOUND_MAILBOXES},
-    settings::SERVER_ADDR,
+    bookkeeping::{
+        CLIENT_ID_TO_SOCKET_ADDRESS, CLIENT_ID_TO_SOCKET_INDEX, CLIENT_OUTBOUND_MAILBOXES,
+    },
+    enque_outbound_messages::send_to_one_client,
+    settings::{BIND_IPV6_DUAL_STACK, MAX_CLIENTS, PROTOCOL_VERSION, RECEIVE_SHARDS_PER_ADDRESS},
+    shutdown::is_shutting_down,
+    traffic_capture::capture_packet,
 };
 
+pub const RECEIVE_BUFFER_SIZE: usize = MAX_PACKET_SIZE as usize;
+// how many datagrams an rx worker pulls off its socket before processing them
+pub const RECEIVE_BATCH_SIZE: usize = 32;
+// messages one client may have waiting for the game loop, which drains them every tick.
+// rate limiting holds a client to a few packets a tick, so this leaves room for bursts
+// and a late tick without one busy client crowding out the rest
+pub const INBOUND_MESSAGES_PER_CLIENT: usize = 16;
+
 lazy_static! {
     pub static ref INCOMING_MESSAGE_QUEUE: Arc<ArrayQueue<ClientToServerMessageBundle>> =
-        Arc::new(ArrayQueue::new(32));
+        Arc::new(ArrayQueue::new(MAX_CLIENTS * INBOUND_MESSAGES_PER_CLIENT));
     pub static ref CLIENT_DISCONNECTED: Arc<RwLock<HashMap<u32, Arc<AtomicBool>>>> =
         Arc::new(RwLock::new(HashMap::new()));
 }
 
 ////////////////////////    CLIENT RX/TX TASKS    ////////////////////////
 
-pub async fn init() -> tokio::io::Result<()> {
-    println!("Initializing socket...");
-    let socket = Arc::new(UdpSocket::bind(SERVER_ADDR).await.unwrap());
-    println!("Socket Initialized!");
+/// Binds `RECEIVE_SHARDS_PER_ADDRESS` sockets per address. Each gets its own rx task,
+/// and the tx task replies to every client from whichever socket it first reached us on.
+/// An address that cannot be bound, like an ipv6 one on a host without ipv6, is skipped
+/// with a warning. Only failing to bind any of them is an error.
+pub async fn init(bind_addresses: &[SocketAddr]) -> tokio::io::Result<()> {
+    println!("Initializing sockets...");
+    let shards_per_address = receive_shards_per_address();
+    let mut sockets = Vec::new();
+    let mut last_error = None;
+    for &bind_address in bind_addresses {
+        let bound = (0..shards_per_address)
+            .map(|_| bind_udp_socket(bind_address, shards_per_address > 1))
+            .collect::<io::Result<Vec<UdpSocket>>>();
+        match bound {
+            Ok(bound) => {
+                for socket in bound {
+                    println!("Listening on {}", socket.local_addr()?);
+                    sockets.push(Arc::new(socket));
+                }
+            }
+            Err(e) => {
+                eprintln!("Skipping bind address {}: {}", bind_address, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    if sockets.is_empty() {
+        return Err(last_error.unwrap_or_else(|| io::Error::other("no bind addresses")));
+    }
+    println!("Sockets Initialized!");
     println!("Spawning rx/tx tasks...");
-    tokio::spawn(continuously_read_any_inbound_messages(socket.clone()));
-    tokio::spawn(continuously_transmit_any_outbound_messages(socket.clone()));
+    for (socket_index, socket) in sockets.iter().enumerate() {
+        tokio::spawn(continuously_read_any_inbound_messages(
+            socket.clone(),
+            socket_index,
+        ));
+    }
+    tokio::spawn(continuously_transmit_any_outbound_messages(sockets));
+    tokio::spawn(continuously_expire_stale_entries());
+    tokio::spawn(continuously_ping_clients());
     Ok(())
 }
 
-pub async fn continuously_read_any_inbound_messages(socket: Arc<UdpSocket>) -> io::Result<()> {
+/// SO_REUSEPORT only load balances datagrams across sockets on linux.
+fn receive_shards_per_address() -> usize {
+    if RECEIVE_SHARDS_PER_ADDRESS > 1 && !cfg!(target_os = "linux") {
+        eprintln!("Receive sharding needs SO_REUSEPORT on linux: using one socket per address");
+        return 1;
+    }
+    RECEIVE_SHARDS_PER_ADDRESS.max(1)
+}
+
+pub fn bind_udp_socket(bind_address: SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(bind_address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if bind_address.is_ipv6() {
+        socket.set_only_v6(!BIND_IPV6_DUAL_STACK)?;
+    }
+    #[cfg(target_os = "linux")]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = reuse_port;
+    socket.set_nonblocking(true)?;
+    socket.bind(&bind_address.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+////////////////////////    RECEIVE BUFFER POOL    ////////////////////////
+/// Buffers owned by one rx worker and reused for every batch it reads.
+pub struct ReceiveBufferPool {
+    buffers: Vec<Vec<u8>>,
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl ReceiveBufferPool {
+    pub fn new(num_buffers: usize) -> Self {
+        Self {
+            buffers: vec![vec![0; RECEIVE_BUFFER_SIZE]; num_buffers.max(1)],
+            received: Vec::with_capacity(num_buffers.max(1)),
+        }
+    }
+
+    /// Waits for one datagram, then drains whatever else is already queued on the
+    /// socket, until it would block or the pool runs out of buffers.
+    pub async fn fill(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        self.received.clear();
+        let first = socket.recv_from(&mut self.buffers[0]).await?;
+        self.received.push(first);
+
+        while self.received.len() < self.buffers.len() {
+            let buffer = &mut self.buffers[self.received.len()];
+            match socket.try_recv_from(buffer) {
+                Ok(received) => self.received.push(received),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .zip(self.buffers.iter())
+            .map(|(&(nbytes, socket_address), buffer)| (&buffer[..nbytes], socket_address))
+    }
+}
+
+pub async fn continuously_read_any_inbound_messages(
+    socket: Arc<UdpSocket>,
+    socket_index: usize,
+) -> io::Result<()> {
     println!("Listening for incoming messages...");
-    let mut buffer = [0; 1024];
+    let mut buffer_pool = ReceiveBufferPool::new(RECEIVE_BATCH_SIZE);
     loop {
-        let (nbytes, socket_address) = socket.recv_from(&mut buffer).await?;
-
-        // check if new client
-        let maybe_client_id: Option<u32> = {
-            let socket_address_to_client_id_read = SOCKET_ADDRESS_TO_CLIENT_ID.read().await;
-            socket_address_to_client_id_read
-                .get(&socket_address)
-                .copied()
-        };
-        let client_id = match maybe_client_id {
-            Some(client_id) => client_id,
-            None => add_client(socket_address).await,
-        };
+        buffer_pool.fill(&socket).await?;
+        for (packet, socket_address) in buffer_pool.packets() {
+            handle_inbound_packet(&socket, socket_index, packet, socket_address).await?;
+        }
+        // tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+    }
+}
 
-        let result: Result<ClientToServerMessage, _> = bincode::deserialize(&buffer[..nbytes]);
-        match result {
-            Ok(result) => {
-                let message_bundle = ClientToServerMessageBundle {
-                    client_id,
-                    message: result,
-                };
-                if INCOMING_MESSAGE_QUEUE.push(message_bundle).is_err() {
-                    eprintln!(
-                        "Inbound message queue full: dropping message from {}",
-                        client_id
-                    );
+async fn handle_inbound_packet(
+    socket: &UdpSocket,
+    socket_index: usize,
+    packet: &[u8],
+    socket_address: SocketAddr,
+) -> io::Result<()> {
+    // blocklisted sources get nothing back, not even a rejection, so a spoofed address
+    // cannot use us to bounce traffic at someone else
+    let ip = canonical_socket_address(socket_address).ip();
+    if is_blocklisted(ip).await {
+        increment(&SERVER_STATS.packets_from_blocklisted);
+        return Ok(());
+    }
+
+    // check if new client
+    let maybe_client_id: Option<u32> = {
+        let socket_address_to_client_id_read = SOCKET_ADDRESS_TO_CLIENT_ID.read().await;
+        socket_address_to_client_id_read
+            .get(&canonical_socket_address(socket_address))
+            .copied()
+    };
+
+    // drop flooding sources before doing any work for them
+    if !allow_packet(ip).await {
+        if let Some(client_id) = maybe_client_id {
+            if let Some(stats) = client_network_stats(client_id).await {
+                stats.record_drop(DropReason::RateLimited);
+            }
+        }
+        return Ok(());
+    }
+
+    capture_packet("client_to_server", packet);
+    let result: Result<ClientToServerMessage, DecodeError> = decode(packet);
+    if result.is_err() {
+        record_malformed_packet(ip).await;
+    }
+
+    let client_id = match maybe_client_id {
+        Some(client_id) => {
+            confirm_pending(client_id).await;
+            client_id
+        }
+        None => {
+            return admit_new_client(socket, socket_index, socket_address, &result).await;
+        }
+    };
+
+    let maybe_stats = client_network_stats(client_id).await;
+    if let Some(stats) = &maybe_stats {
+        stats.record_in(packet.len());
+    }
+
+    match result {
+        Ok(ClientToServerMessage::ConnectRequest { .. }) => {
+            // the client never heard back, so tell it its id again
+            if let Some(stats) = &maybe_stats {
+                stats.record_retransmit();
+            }
+            let new_id_message = ServerToClientMessage::ClientIDAssignment {
+                new_client_id: client_id,
+            };
+            send_to_one_client(client_id, new_id_message).await;
+        }
+        Ok(ClientToServerMessage::Ping { sequence }) => {
+            send_to_one_client(client_id, ServerToClientMessage::Pong { sequence }).await;
+        }
+        Ok(ClientToServerMessage::Pong { sequence }) => {
+            if let Some(stats) = &maybe_stats {
+                stats.finish_ping(sequence);
+            }
+        }
+        Ok(result) => {
+            let message_bundle = ClientToServerMessageBundle {
+                client_id,
+                message: result,
+            };
+            if INCOMING_MESSAGE_QUEUE.push(message_bundle).is_err() {
+                eprintln!(
+                    "Inbound message queue full: dropping message from {}",
+                    client_id
+                );
+                if let Some(stats) = &maybe_stats {
+                    stats.record_drop(DropReason::InboundQueueFull);
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("Error parsing client data: {}", e);
+            if let Some(stats) = &maybe_stats {
+                stats.record_drop(DropReason::Malformed);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Strangers have to introduce themselves with a `ConnectRequest` before they get a client id.
+async fn admit_new_client(
+    socket: &UdpSocket,
+    socket_index: usize,
+    socket_address: SocketAddr,
+    result: &Result<ClientToServerMessage, DecodeError>,
+) -> io::Result<()> {
+    let protocol_version = match result {
+        Ok(ClientToServerMessage::ConnectRequest { protocol_version }) => *protocol_version,
+        _ => return Ok(()),
+    };
+    if !allow_connection_attempt(canonical_socket_address(socket_address).ip()).await {
+        return Ok(());
+    }
+
+    let num_clients = CLIENT_OUTBOUND_MAILBOXES.read().await.len();
+    let rejection = if is_shutting_down() {
+        Some(RejectionReason::ShuttingDown)
+    } else if protocol_version != PROTOCOL_VERSION {
+        Some(RejectionReason::VersionMismatch {
+            server_version: PROTOCOL_VERSION,
+        })
+    } else if num_clients >= MAX_CLIENTS {
+        Some(RejectionReason::ServerFull)
+    } else {
+        None
+    };
+
+    if let Some(reason) = rejection {
+        increment(&SERVER_STATS.connections_rejected);
+        println!("Rejected {}: {}", socket_address, reason);
+        let message = ServerToClientMessage::ConnectionRejected { reason };
+        match encode(&message) {
+            Ok(binary_message) => {
+                // a stranger we cannot reach is not worth stopping this socket's rx task over
+                if let Err(e) = socket.send_to(&binary_message, socket_address).await {
+                    eprintln!("Error sending rejection to {}: {:?}", socket_address, e);
                 }
             }
             Err(e) => {
-                eprintln!("Error parsing client data: {:?}", e);
+                eprintln!("Error serializing message: {:?}", e);
             }
         }
-        // tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+        return Ok(());
     }
+
+    let client_id = add_client(socket_address, socket_index).await;
+    mark_pending(client_id).await;
+    Ok(())
 }
 
-pub async fn continuously_transmit_any_outbound_messages(socket: Arc<UdpSocket>) -> io::Result<()> {
+pub async fn continuously_transmit_any_outbound_messages(
+    sockets: Vec<Arc<UdpSocket>>,
+) -> io::Result<()> {
     // transmit any outbound messages
     loop {
         // loop through every mailbox
@@ -97,6 +350,23 @@ pub async fn continuously_transmit_any_outbound_messages(socket: Arc<UdpSocket>)
                 continue;
             }
 
+            // and which of our sockets did he come in on?
+            let maybe_socket: Option<&Arc<UdpSocket>> = {
+                let client_id_to_socket_index_read = CLIENT_ID_TO_SOCKET_INDEX.read().await;
+                client_id_to_socket_index_read
+                    .get(&client_id)
+                    .and_then(|&socket_index| sockets.get(socket_index))
+            };
+            let socket = match maybe_socket {
+                Some(socket) => socket,
+                None => {
+                    eprintln!("Failed to find socket for client {}", client_id);
+                    continue;
+                }
+            };
+
+            let maybe_stats = client_network_stats(client_id).await;
+
             // if yes, send his messages
             if let Some(socket_address) = maybe_socket_address {
                 // send messages if theres a registered socket for this client
@@ -107,9 +377,12 @@ pub async fn continuously_transmit_any_outbound_messages(socket: Arc<UdpSocket>)
                     if messages_sent_this_client >= MAX_MESSAGES_PER_CLIENT_FRAME {
                         break;
                     }
-                    match bincode::serialize(&message) {
+                    match encode(&message) {
                         Ok(binary_message) => {
                             socket.send_to(&binary_message, socket_address).await?;
+                            if let Some(stats) = &maybe_stats {
+                                stats.record_out(binary_message.len());
+                            }
                         }
                         Err(e) => {
                             eprintln!("Error serializing message: {:?}", e);
@@ -122,3 +395,38 @@ pub async fn continuously_transmit_any_outbound_messages(socket: Arc<UdpSocket>)
         // tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
     }
 }
+
+/// Pings every client once per `PING_INTERVAL` so their rtt and loss stay current.
+/// A client that has stopped answering is disconnected, so a crashed one gives its
+/// slot back.
+pub async fn continuously_ping_clients() {
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+    loop {
+        interval.tick().await;
+        let clients: Vec<(u32, Arc<ConnectionStats>)> = {
+            let client_network_stats_read = CLIENT_NETWORK_STATS.read().await;
+            client_network_stats_read
+                .iter()
+                .map(|(&client_id, stats)| (client_id, stats.clone()))
+                .collect()
+        };
+        for (client_id, stats) in clients {
+            if stats.is_unresponsive() {
+                eprintln!("Client {} stopped answering pings", client_id);
+                let to_self_message = ClientToServerMessageBundle {
+                    client_id,
+                    message: ClientToServerMessage::Disconnect,
+                };
+                if INCOMING_MESSAGE_QUEUE.push(to_self_message).is_err() {
+                    eprintln!(
+                        "Inbound message queue full: dropping disconnect message from {}",
+                        client_id
+                    );
+                }
+                continue;
+            }
+            let sequence = stats.start_ping();
+            send_to_one_client(client_id, ServerToClientMessage::Ping { sequence }).await;
+        }
+    }
+}
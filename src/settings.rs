@@ -1 +1,64 @@
-pub const SERVER_ADDR: &str = "127.0.0.1:8080";
+use glam::Vec2;
+
+// where the client looks for a server, overridable with the first command line argument
+pub const SERVER_ADDR: &str = "localhost:8080";
+
+// what the server listens on, overridable by passing addresses on the command line.
+// any that cannot be bound, like [::1] on a host without ipv6, are skipped
+pub const SERVER_BIND_ADDRS: &[&str] = &["127.0.0.1:8080", "[::1]:8080"];
+// lets an ipv6 wildcard bind like [::]:8080 also accept ipv4 clients
+pub const BIND_IPV6_DUAL_STACK: bool = true;
+// more than one opens that many SO_REUSEPORT sockets per bind address, each with its own
+// rx task, so the kernel spreads clients across them. linux only
+pub const RECEIVE_SHARDS_PER_ADDRESS: usize = 1;
+
+// bump whenever the wire format changes in a way old peers cannot read
+pub const PROTOCOL_VERSION: u32 = 11;
+
+pub const MAX_CLIENTS: usize = 32;
+// a client that sends this many messages the server has to refuse, like moving someone
+// else's player, gets kicked
+pub const KICK_AFTER_VIOLATIONS: u32 = 10;
+
+// the server simulates this many ticks a second, and clients predict at the same rate
+pub const TICKS_PER_SECOND: u32 = 60;
+// snapshots the server sends a second, should divide TICKS_PER_SECOND
+pub const SNAPSHOTS_PER_SECOND: u32 = 20;
+// a server that falls behind runs at most this many ticks at once to catch up, and
+// skips whatever is left
+pub const MAX_CATCH_UP_TICKS: u32 = 4;
+
+pub const PERSIST_STATE_ON_SHUTDOWN: bool = false;
+pub const STATE_SAVE_PATH: &str = "server_state.bin";
+
+// replicated positions are quantized to this box, the same as graphics::DIMS, and
+// anything outside it is clamped to the edge
+pub const WORLD_MIN: Vec2 = Vec2::new(0.0, 0.0);
+pub const WORLD_MAX: Vec2 = Vec2::new(240.0, 160.0);
+pub const POSITION_PRECISION: f32 = 1.0 / 32.0;
+// per tick, on each axis
+pub const VELOCITY_LIMIT: f32 = 8.0;
+pub const VELOCITY_PRECISION: f32 = 1.0 / 64.0;
+// rotations are unit vectors, so each axis is within -1..=1
+pub const ROTATION_PRECISION: f32 = 1.0 / 256.0;
+// no shape is bigger than this on either axis
+pub const SHAPE_MAX: f32 = 64.0;
+pub const SHAPE_PRECISION: f32 = 1.0 / 8.0;
+
+// how far a player moves per tick at full tilt, the server simulates this and clients
+// only send what they press
+pub const PLAYER_SPEED: f32 = 1.0;
+pub const SPRINT_MULTIPLIER: f32 = 2.0;
+// input axes are within -1..=1
+pub const INPUT_AXIS_PRECISION: f32 = 1.0 / 64.0;
+
+// clients draw other entities this many server ticks in the past, two snapshots at 20 a
+// second, so there is nearly always a newer state to move towards
+pub const INTERPOLATION_DELAY_TICKS: f64 = 6.0;
+// when snapshots stop coming an entity carries on at its last velocity for this long,
+// then stops where it is
+pub const MAX_EXTRAPOLATION_TICKS: f64 = 12.0;
+
+// hits from laggy clients are judged against where things were up to this many server
+// ticks ago, half a second, and no further
+pub const MAX_REWIND_TICKS: u32 = 30;
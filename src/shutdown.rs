@@ -0,0 +1,103 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    bookkeeping::CLIENT_OUTBOUND_MAILBOXES,
+    enque_outbound_messages::broadcast_to_all_connected,
+    server_state::ServerState,
+    server_to_client::ServerToClientMessage,
+    settings::{PERSIST_STATE_ON_SHUTDOWN, STATE_SAVE_PATH},
+};
+
+// how long to wait for mailboxes to drain before giving up on stragglers
+const SHUTDOWN_FLUSH_DEADLINE: Duration = Duration::from_secs(2);
+// the tx task may still be mid send_to after the last pop
+const SHUTDOWN_SEND_GRACE: Duration = Duration::from_millis(50);
+
+lazy_static! {
+    pub static ref SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+    pub static ref SHUTDOWN_REASON: Mutex<String> = Mutex::new(String::new());
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+pub fn request_shutdown(reason: &str) {
+    *SHUTDOWN_REASON.lock().unwrap() = reason.to_string();
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+////////////////////////    SIGNAL HANDLING    ////////////////////////
+pub fn spawn_signal_listener() {
+    tokio::spawn(async {
+        let signal_name = wait_for_shutdown_signal().await;
+        println!("Received {}, shutting down...", signal_name);
+        request_shutdown(&format!("server is shutting down ({})", signal_name));
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            eprintln!("Failed to install SIGTERM handler: {:?}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = sigterm.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
+
+////////////////////////    SHUTDOWN SEQUENCE    ////////////////////////
+/// Tells every client why the server is going away, waits for the tx task to drain
+/// their mailboxes, and saves the world if configured to.
+pub async fn graceful_shutdown(state: &ServerState) {
+    let reason = SHUTDOWN_REASON.lock().unwrap().clone();
+    broadcast_to_all_connected(ServerToClientMessage::ServerShutdown { reason }).await;
+
+    let deadline = Instant::now() + SHUTDOWN_FLUSH_DEADLINE;
+    loop {
+        let all_flushed = {
+            let clients_read = CLIENT_OUTBOUND_MAILBOXES.read().await;
+            clients_read.values().all(|queue| queue.is_empty())
+        };
+        if all_flushed {
+            tokio::time::sleep(SHUTDOWN_SEND_GRACE).await;
+            break;
+        }
+        if Instant::now() > deadline {
+            eprintln!("Shutdown deadline hit: some clients may not have been notified");
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    if PERSIST_STATE_ON_SHUTDOWN {
+        match state.save_to_file(STATE_SAVE_PATH) {
+            Ok(()) => println!("Saved server state to {}", STATE_SAVE_PATH),
+            Err(e) => eprintln!("Failed to save server state: {:?}", e),
+        }
+    }
+
+    println!("Server shut down.");
+}
@@ -0,0 +1,303 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    bitpack::{BitPack, BitReader, BitWriter, Quantization, Vec2Quantization},
+    replication::{ComponentData, EntityState},
+    settings::{POSITION_PRECISION, VELOCITY_LIMIT, VELOCITY_PRECISION, WORLD_MAX, WORLD_MIN},
+};
+
+// how many past snapshots either side keeps to diff against, about 1.6s at 20 a second.
+// an ack older than this gets a full snapshot instead of a delta
+pub const SNAPSHOT_HISTORY_LENGTH: usize = 32;
+
+pub type EntityStates = HashMap<u32, EntityState>;
+
+pub const POSITION_QUANTIZATION: Vec2Quantization = Vec2Quantization {
+    x: Quantization::new(WORLD_MIN.x, WORLD_MAX.x, POSITION_PRECISION),
+    y: Quantization::new(WORLD_MIN.y, WORLD_MAX.y, POSITION_PRECISION),
+};
+pub const VELOCITY_QUANTIZATION: Vec2Quantization = Vec2Quantization {
+    x: Quantization::new(-VELOCITY_LIMIT, VELOCITY_LIMIT, VELOCITY_PRECISION),
+    y: Quantization::new(-VELOCITY_LIMIT, VELOCITY_LIMIT, VELOCITY_PRECISION),
+};
+
+////////////////////////    HISTORY    ////////////////////////
+pub struct SnapshotHistory {
+    snapshots: VecDeque<(u32, EntityStates)>,
+}
+
+impl SnapshotHistory {
+    pub fn new() -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(SNAPSHOT_HISTORY_LENGTH),
+        }
+    }
+
+    pub fn push(&mut self, tick: u32, entities: EntityStates) {
+        if self.snapshots.len() == SNAPSHOT_HISTORY_LENGTH {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((tick, entities));
+    }
+
+    pub fn get(&self, tick: u32) -> Option<&EntityStates> {
+        self.snapshots
+            .iter()
+            .find(|(t, _)| *t == tick)
+            .map(|(_, entities)| entities)
+    }
+}
+
+impl Default for SnapshotHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+////////////////////////    TICKS    ////////////////////////
+/// Whether tick `a` is after tick `b`. Ticks go out as their low 32 bits, so they are
+/// compared as sequence numbers: `a` is newer if it is less than half the range ahead.
+pub fn tick_is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// The full tick a wire tick stands for, taken to be the one nearest `near`, a full
+/// tick we already know. Lets clients keep a tick that only goes up.
+pub fn unwrap_tick(tick: u32, near: u64) -> u64 {
+    let delta = tick.wrapping_sub(near as u32) as i32;
+    near.saturating_add_signed(delta as i64)
+}
+
+////////////////////////    DELTAS    ////////////////////////
+/// Everything it takes to turn `baseline` into `current`: entities that are new, the
+/// components that changed or went away on the others, and entities that are gone.
+/// Without a baseline every entity is new, which is a full snapshot.
+pub fn diff(
+    tick: u32,
+    baseline: Option<(u32, &EntityStates)>,
+    current: &EntityStates,
+) -> WorldUpdate {
+    let empty = EntityStates::new();
+    let (baseline_tick, baseline) = match baseline {
+        Some((baseline_tick, baseline)) => (Some(baseline_tick), baseline),
+        None => (None, &empty),
+    };
+
+    let mut spawned = Vec::new();
+    let mut updated = Vec::new();
+    for (&entity_id, entity) in current.iter() {
+        let Some(old) = baseline.get(&entity_id) else {
+            spawned.push(SpawnedEntity {
+                entity_id,
+                components: entity.components().copied().collect(),
+            });
+            continue;
+        };
+        let changed: Vec<ComponentData> = entity
+            .components()
+            .filter(|data| old.get_data(data.component_id()) != Some(data))
+            .copied()
+            .collect();
+        let removed: Vec<u32> = old
+            .components()
+            .map(|data| data.component_id())
+            .filter(|&component_id| entity.get_data(component_id).is_none())
+            .collect();
+        if !changed.is_empty() || !removed.is_empty() {
+            updated.push(UpdatedEntity {
+                entity_id,
+                changed,
+                removed,
+            });
+        }
+    }
+
+    let despawned = baseline
+        .keys()
+        .filter(|entity_id| !current.contains_key(entity_id))
+        .copied()
+        .collect();
+
+    WorldUpdate {
+        tick,
+        baseline_tick,
+        spawned,
+        updated,
+        despawned,
+    }
+}
+
+/// Rebuilds the full state an update describes from its baseline, which is empty for
+/// a full snapshot. None if it updates an entity the baseline does not have, which
+/// means the update was not made against this baseline.
+pub fn apply_update(baseline: &EntityStates, update: &WorldUpdate) -> Option<EntityStates> {
+    let mut entities = baseline.clone();
+    for entity_id in &update.despawned {
+        entities.remove(entity_id);
+    }
+    for spawned in &update.spawned {
+        let mut entity = EntityState::new();
+        for data in &spawned.components {
+            entity.insert_data(*data);
+        }
+        entities.insert(spawned.entity_id, entity);
+    }
+    for updated in &update.updated {
+        let entity = entities.get_mut(&updated.entity_id)?;
+        for &component_id in &updated.removed {
+            entity.remove(component_id);
+        }
+        for data in &updated.changed {
+            entity.insert_data(*data);
+        }
+    }
+    Some(entities)
+}
+
+/// Leaves entities out of `update` until it packs into `max_bytes`, keeping despawns,
+/// then spawns, then updates, in that order, so a big world still arrives while it
+/// moves. Whatever is left out is still different from what the client ends up with,
+/// so the next update against it carries the rest.
+pub fn fit_update(update: &mut WorldUpdate, max_bytes: usize) {
+    let header = WorldUpdate {
+        spawned: Vec::new(),
+        updated: Vec::new(),
+        despawned: Vec::new(),
+        ..*update
+    };
+    // room for the three list lengths at their longest
+    let overhead = packed_bits(&header) + 3 * packed_bits(&u32::MAX);
+    let mut bits_left = (max_bytes * 8).saturating_sub(overhead);
+    let mut fits = |bits: usize| {
+        if bits > bits_left {
+            bits_left = 0;
+            return false;
+        }
+        bits_left -= bits;
+        true
+    };
+    update
+        .despawned
+        .retain(|entity_id| fits(packed_bits(entity_id)));
+    update.spawned.retain(|spawned| fits(packed_bits(spawned)));
+    update.updated.retain(|updated| fits(packed_bits(updated)));
+}
+
+fn packed_bits<T: BitPack>(item: &T) -> usize {
+    let mut writer = BitWriter::new();
+    item.pack(&mut writer);
+    writer.bits_used()
+}
+
+////////////////////////    WIRE LAYOUT    ////////////////////////
+#[derive(Debug, Clone)]
+pub struct SpawnedEntity {
+    pub entity_id: u32,
+    pub components: Vec<ComponentData>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdatedEntity {
+    pub entity_id: u32,
+    pub changed: Vec<ComponentData>,
+    // component ids
+    pub removed: Vec<u32>,
+}
+
+/// Spawns, updates and despawns since `baseline_tick`, or since nothing at all.
+#[derive(Debug, Clone)]
+pub struct WorldUpdate {
+    pub tick: u32,
+    pub baseline_tick: Option<u32>,
+    pub spawned: Vec<SpawnedEntity>,
+    pub updated: Vec<UpdatedEntity>,
+    pub despawned: Vec<u32>,
+}
+
+impl BitPack for SpawnedEntity {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.entity_id);
+        self.components.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(SpawnedEntity {
+            entity_id: reader.read_varint()?,
+            components: Vec::unpack(reader)?,
+        })
+    }
+}
+
+impl BitPack for UpdatedEntity {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_varint(self.entity_id);
+        self.changed.pack(writer);
+        self.removed.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        Some(UpdatedEntity {
+            entity_id: reader.read_varint()?,
+            changed: Vec::unpack(reader)?,
+            removed: Vec::unpack(reader)?,
+        })
+    }
+}
+
+impl BitPack for WorldUpdate {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write(self.tick as u64, 32);
+        writer.write_bool(self.baseline_tick.is_some());
+        if let Some(baseline_tick) = self.baseline_tick {
+            // the baseline is a recent tick, so only how far back it is goes out
+            writer.write_varint(self.tick.wrapping_sub(baseline_tick));
+        }
+        self.spawned.pack(writer);
+        self.updated.pack(writer);
+        self.despawned.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Option<Self> {
+        let tick = reader.read(32)? as u32;
+        let baseline_tick = if reader.read_bool()? {
+            Some(tick.wrapping_sub(reader.read_varint()?))
+        } else {
+            None
+        };
+        Some(WorldUpdate {
+            tick,
+            baseline_tick,
+            spawned: Vec::unpack(reader)?,
+            updated: Vec::unpack(reader)?,
+            despawned: Vec::unpack(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_ticks_are_newer_across_the_wrap() {
+        assert!(tick_is_newer(11, 10));
+        assert!(!tick_is_newer(10, 11));
+        assert!(!tick_is_newer(10, 10));
+        assert!(tick_is_newer(2, u32::MAX - 2));
+        assert!(!tick_is_newer(u32::MAX - 2, 2));
+    }
+
+    #[test]
+    fn wire_ticks_unwrap_to_the_nearest_full_tick() {
+        assert_eq!(unwrap_tick(13, 10), 13);
+        assert_eq!(unwrap_tick(7, 10), 7);
+
+        let before_wrap = u32::MAX as u64 - 2;
+        assert_eq!(unwrap_tick(3, before_wrap), u32::MAX as u64 + 4);
+        let after_wrap = u32::MAX as u64 + 4;
+        assert_eq!(unwrap_tick(u32::MAX - 2, after_wrap), before_wrap);
+
+        // nothing comes before tick 0
+        assert_eq!(unwrap_tick(u32::MAX, 0), 0);
+    }
+}
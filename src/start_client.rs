@@ -1,29 +1,48 @@
 use {
-    client_game::process_message_queue, client_to_server::ClientToServerMessage,
-    event_processing::process_events_and_input, state::State,
+    bitpack::Packed,
+    client_game::{join_game, process_message_queue},
+    client_to_server::ClientToServerMessage,
+    client_udp_networking::enqueue_outbound_message,
+    event_processing::{process_events_and_input, sample_input},
+    settings::TICKS_PER_SECOND,
+    state::State,
 };
 
+mod bitpack;
 mod bookkeeping;
 mod client_game;
 mod client_to_server;
 mod client_udp_networking;
+mod codec;
 mod components;
 mod draw;
 mod enque_outbound_messages;
 mod event_processing;
 mod game_objects;
 mod graphics;
+mod input;
+mod lag_compensation;
+mod network_stats;
+mod rate_limiting;
+mod replication;
+mod rpc;
 mod server_game;
 mod server_state;
+mod server_stats;
 mod server_to_client;
 mod server_udp_networking;
 mod settings;
+mod shutdown;
+mod snapshot;
 mod state;
+mod systems;
+mod tick_scheduler;
+mod traffic_capture;
+mod validation;
+mod wire_enum;
 
-pub const FRAMES_PER_SECOND: u32 = 60;
-const TIMESTEP: f32 = 1.0 / FRAMES_PER_SECOND as f32;
-
-const POSITION_TRANSMIT_FREQUENCY: u32 = 1;
+// the same rate the server ticks at, so predicted players move as fast as real ones
+const TIMESTEP: f32 = 1.0 / TICKS_PER_SECOND as f32;
 
 #[derive(PartialEq, Eq)]
 enum Bool {
@@ -33,61 +52,33 @@ enum Bool {
 
 #[tokio::main]
 async fn main() -> tokio::io::Result<()> {
-    let result = client_udp_networking::init_connection().await;
-    if let Err(e) = result {
-        eprintln!("Error connecting to server: {:?}", e);
-        return Ok(());
-    }
-
-    // request a new player
-    if client_udp_networking::OUTBOUND_MESSAGE_QUEUE
-        .push(ClientToServerMessage::RequestToSpawnPlayer)
-        .is_err()
-    {
-        eprintln!("Outbound message queue full: dropping message");
-    }
+    // server address comes from the command line, falling back to the default in settings
+    let server_addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| settings::SERVER_ADDR.to_string());
+
+    let client_id = match client_udp_networking::connect_to_server(&server_addr).await {
+        Ok(client_id) => client_id,
+        Err(e) => {
+            eprintln!("Could not join server: {}", e);
+            return Ok(());
+        }
+    };
+    println!("joined server as client {}", client_id);
 
-    // request all players
-    if client_udp_networking::OUTBOUND_MESSAGE_QUEUE
-        .push(ClientToServerMessage::RequestAllPlayers)
-        .is_err()
-    {
-        eprintln!("Outbound message queue full: dropping message");
+    let mut state = State::new();
+    if let Err(e) = join_game(&mut state, client_id).await {
+        eprintln!("Could not join game: {}", e);
+        return Ok(());
     }
 
     let (mut rl, mut rlt, mut render_texture) = graphics::init_graphics();
 
     ////////////////    MAIN LOOP    ////////////////
-    let mut state = State::new();
-
-    let mut position_transmit_counter = POSITION_TRANSMIT_FREQUENCY;
 
     while !rl.window_should_close() {
         process_events_and_input(&mut rl, &mut state);
 
-        // state transmitting
-        {
-            position_transmit_counter -= 1;
-            if position_transmit_counter == 0 {
-                position_transmit_counter = POSITION_TRANSMIT_FREQUENCY;
-
-                for player in state.players.values() {
-                    if let Some(client_id) = state.client_id {
-                        if player.owner_client_id == client_id
-                            && client_udp_networking::OUTBOUND_MESSAGE_QUEUE
-                                .push(ClientToServerMessage::EntityPosition {
-                                    entity_id: player.entity_id,
-                                    pos: player.pos,
-                                })
-                                .is_err()
-                        {
-                            eprintln!("Outbound message queue full: dropping message");
-                        }
-                    }
-                }
-            }
-        }
-
         process_message_queue(&mut state).await;
 
         let dt = rl.get_frame_time();
@@ -95,8 +86,16 @@ async fn main() -> tokio::io::Result<()> {
         while state.time_since_last_update > TIMESTEP {
             state.time_since_last_update -= TIMESTEP;
 
+            // one command per tick, the same rate the server simulates them at
+            let command = sample_input(&rl, &mut state);
+            enqueue_outbound_message(ClientToServerMessage::Input {
+                command: Packed(command),
+            });
+            client_game::predict(&mut state, command);
+
             client_game::step(&mut state);
         }
+        client_game::interpolate(&mut state, dt);
 
         graphics::render(&mut rl, &mut rlt, &mut render_texture, &state);
 
@@ -104,5 +103,11 @@ async fn main() -> tokio::io::Result<()> {
             break;
         }
     }
+
+    // let the server despawn our player rather than leave it standing there
+    if state.disconnect_reason.is_none() {
+        enqueue_outbound_message(ClientToServerMessage::Disconnect);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
     Ok(())
 }
@@ -1,24 +1,68 @@
+mod bitpack;
 mod bookkeeping;
 mod client_game;
 mod client_to_server;
 mod client_udp_networking;
+mod codec;
 mod components;
 mod draw;
 mod enque_outbound_messages;
 mod event_processing;
 mod game_objects;
 mod graphics;
+mod input;
+mod lag_compensation;
+mod network_stats;
+mod rate_limiting;
+mod replication;
+mod rpc;
 mod server_game;
 mod server_state;
+mod server_stats;
 mod server_to_client;
 mod server_udp_networking;
 mod settings;
+mod shutdown;
+mod snapshot;
 mod state;
+mod systems;
+mod tick_scheduler;
+mod traffic_capture;
+mod validation;
+mod wire_enum;
+
+use std::net::SocketAddr;
+
+use settings::SERVER_BIND_ADDRS;
 
 #[tokio::main]
 async fn main() {
-    let _ = server_udp_networking::init().await;
+    // bind addresses come from the command line, falling back to the defaults in settings
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let bind_address_strings: Vec<&str> = if args.is_empty() {
+        SERVER_BIND_ADDRS.to_vec()
+    } else {
+        args.iter().map(|arg| arg.as_str()).collect()
+    };
+    let mut bind_addresses: Vec<SocketAddr> = Vec::new();
+    for bind_address_string in bind_address_strings {
+        match bind_address_string.parse() {
+            Ok(bind_address) => bind_addresses.push(bind_address),
+            Err(e) => {
+                eprintln!("Invalid bind address {}: {:?}", bind_address_string, e);
+                return;
+            }
+        }
+    }
+
+    if let Err(e) = server_udp_networking::init(&bind_addresses).await {
+        eprintln!("Error initializing server sockets: {:?}", e);
+        return;
+    }
+
+    shutdown::spawn_signal_listener();
 
     let mut state = server_state::ServerState::new();
     server_game::main_loop(&mut state).await;
+    shutdown::graceful_shutdown(&state).await;
 }
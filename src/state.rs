@@ -1,12 +1,35 @@
-use std::collections::HashMap;
+use glam::Vec2;
+use hecs::World;
+use raylib::prelude::Color;
 
-use crate::game_objects::Player;
+use crate::{components::Registry, snapshot::SnapshotHistory};
+
+/// Where a despawned entity was and how it looked, kept around for a few frames so it
+/// can fade out instead of vanishing.
+pub struct DespawnFade {
+    pub pos: Vec2,
+    pub dims: Vec2,
+    pub color: Color,
+    pub frames_left: u32,
+}
 
 pub struct State {
     pub running: bool,
     pub time_since_last_update: f32,
     pub client_id: Option<u32>,
-    pub players: HashMap<u32, Player>,
+    // sequence number of the last input command we sent
+    pub input_sequence: u32,
+    // tick of the newest snapshot applied, unwrapped from the wire so it only goes up
+    pub server_tick: u64,
+    // where we think the server is now, in ticks, moved on every frame
+    pub server_time: f64,
+    // full states of recent snapshots, the baselines deltas are applied to
+    pub snapshot_history: SnapshotHistory,
+    // every entity the server replicates to us, by its network id
+    pub world: World,
+    pub registry: Registry,
+    pub despawn_fades: Vec<DespawnFade>,
+    pub disconnect_reason: Option<String>,
 }
 
 impl State {
@@ -15,7 +38,14 @@ impl State {
             running: true,
             time_since_last_update: 0.0,
             client_id: None,
-            players: HashMap::new(),
+            input_sequence: 0,
+            server_tick: 0,
+            server_time: 0.0,
+            snapshot_history: SnapshotHistory::new(),
+            world: World::new(),
+            registry: Registry::new(),
+            despawn_fades: Vec::new(),
+            disconnect_reason: None,
         }
     }
 }
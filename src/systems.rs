@@ -0,0 +1,173 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// When in a tick a system runs. Stages run in this order, and the systems within a
+/// stage in the order they were added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    // turn what clients sent into intentions, like velocities
+    Input,
+    // move things and resolve what touches what
+    Simulation,
+    // scores, damage, spawning and despawning
+    GameRules,
+    // keep what clients need to see, snapshots go out after the tick
+    Replication,
+    // drop whatever the tick left behind
+    Cleanup,
+}
+
+pub type System<S> = fn(&mut S);
+
+struct RegisteredSystem<S> {
+    stage: Stage,
+    name: &'static str,
+    system: System<S>,
+    // since the last report, so they can be read and reset from a shared reference
+    runs: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+/// Everything that happens to state `S` in one tick, in order. New behaviour is a new
+/// system added where the rest are, the main loop never changes.
+pub struct Systems<S> {
+    systems: Vec<RegisteredSystem<S>>,
+}
+
+impl<S> Systems<S> {
+    pub fn new() -> Self {
+        Self {
+            systems: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, stage: Stage, name: &'static str, system: System<S>) -> &mut Self {
+        // after everything already in this stage or an earlier one
+        let index = self
+            .systems
+            .partition_point(|registered| registered.stage <= stage);
+        self.systems.insert(
+            index,
+            RegisteredSystem {
+                stage,
+                name,
+                system,
+                runs: AtomicU64::new(0),
+                total_nanos: AtomicU64::new(0),
+                max_nanos: AtomicU64::new(0),
+            },
+        );
+        self
+    }
+
+    /// Runs every system once, timing each.
+    pub fn run(&self, state: &mut S) {
+        for registered in self.systems.iter() {
+            let started = Instant::now();
+            (registered.system)(state);
+            let nanos = started.elapsed().as_nanos() as u64;
+            registered.runs.fetch_add(1, Ordering::Relaxed);
+            registered.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+            registered.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        }
+    }
+
+    /// Prints how long each system took since the last report, and starts counting
+    /// afresh.
+    pub fn report_times(&self) {
+        for registered in self.systems.iter() {
+            let runs = registered.runs.swap(0, Ordering::Relaxed);
+            let total_nanos = registered.total_nanos.swap(0, Ordering::Relaxed);
+            let max_nanos = registered.max_nanos.swap(0, Ordering::Relaxed);
+            if runs == 0 {
+                continue;
+            }
+            println!(
+                "  {:?} {}: mean {}us, max {}us",
+                registered.stage,
+                registered.name,
+                total_nanos / runs / 1000,
+                max_nanos / 1000
+            );
+        }
+    }
+}
+
+impl<S> Default for Systems<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Log = Vec<&'static str>;
+
+    fn first_input(log: &mut Log) {
+        log.push("first input");
+    }
+    fn second_input(log: &mut Log) {
+        log.push("second input");
+    }
+    fn simulation(log: &mut Log) {
+        log.push("simulation");
+    }
+    fn game_rules(log: &mut Log) {
+        log.push("game rules");
+    }
+    fn first_replication(log: &mut Log) {
+        log.push("first replication");
+    }
+    fn second_replication(log: &mut Log) {
+        log.push("second replication");
+    }
+    fn cleanup(log: &mut Log) {
+        log.push("cleanup");
+    }
+
+    #[test]
+    fn systems_run_by_stage_then_in_the_order_they_were_added() {
+        let mut systems = Systems::new();
+        systems
+            .add(Stage::Cleanup, "cleanup", cleanup)
+            .add(Stage::Replication, "first replication", first_replication)
+            .add(Stage::Input, "first input", first_input)
+            .add(Stage::GameRules, "game rules", game_rules)
+            .add(Stage::Simulation, "simulation", simulation)
+            .add(Stage::Replication, "second replication", second_replication)
+            .add(Stage::Input, "second input", second_input);
+
+        let mut log = Log::new();
+        systems.run(&mut log);
+        assert_eq!(
+            log,
+            [
+                "first input",
+                "second input",
+                "simulation",
+                "game rules",
+                "first replication",
+                "second replication",
+                "cleanup"
+            ]
+        );
+    }
+
+    #[test]
+    fn every_run_is_timed_until_the_report() {
+        let mut systems = Systems::new();
+        systems.add(Stage::Input, "first input", first_input);
+        let mut log = Log::new();
+        systems.run(&mut log);
+        systems.run(&mut log);
+        assert_eq!(systems.systems[0].runs.load(Ordering::Relaxed), 2);
+
+        systems.report_times();
+        assert_eq!(systems.systems[0].runs.load(Ordering::Relaxed), 0);
+    }
+}
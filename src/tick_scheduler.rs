@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+use crate::settings::MAX_CATCH_UP_TICKS;
+
+// how often tick times are printed
+const STATS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long ticks took since the last report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TickStats {
+    pub ticks: u64,
+    pub total: Duration,
+    pub max: Duration,
+    // ticks that took longer than a timestep to simulate
+    pub overruns: u64,
+    // ticks run after their deadline had already passed, to catch up
+    pub late: u64,
+    // ticks dropped because catching up on them would only have put us further behind
+    pub skipped: u64,
+}
+
+impl TickStats {
+    pub fn mean(&self) -> Duration {
+        if self.ticks == 0 {
+            return Duration::ZERO;
+        }
+        self.total / self.ticks as u32
+    }
+}
+
+/// Runs the simulation at a fixed rate. It owns the tick number, which only ever goes
+/// up, and the deadline of the next tick. A server that falls behind runs at most
+/// `MAX_CATCH_UP_TICKS` at once and skips the rest, rather than spending ever longer
+/// catching up.
+pub struct TickScheduler {
+    timestep: Duration,
+    tick: u64,
+    next_deadline: Instant,
+    stats: TickStats,
+    last_report: Instant,
+}
+
+impl TickScheduler {
+    pub fn new(ticks_per_second: u32) -> Self {
+        let now = Instant::now();
+        let timestep = Duration::from_secs(1) / ticks_per_second;
+        Self {
+            timestep,
+            tick: 0,
+            next_deadline: now + timestep,
+            stats: TickStats::default(),
+            last_report: now,
+        }
+    }
+
+    /// The last tick that was simulated.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Counts a new tick and returns it.
+    pub fn advance(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Makes the first tick due a timestep from now, for when the loop starts a while
+    /// after the scheduler was made.
+    pub fn start(&mut self) {
+        let now = Instant::now();
+        self.next_deadline = now + self.timestep;
+        self.last_report = now;
+    }
+
+    /// Waits until the next tick is due. tokio's timer only wakes to the millisecond, so
+    /// this may return up to a millisecond late. The deadlines do not drift with it,
+    /// `due_ticks` runs a late tick straight after.
+    pub async fn wait(&self) {
+        tokio::time::sleep_until(self.next_deadline.into()).await;
+    }
+
+    /// How many ticks to run now. Each one moves the deadline on by a timestep.
+    pub fn due_ticks(&mut self) -> u32 {
+        let now = Instant::now();
+        let mut due = 0;
+        while self.next_deadline <= now && due < MAX_CATCH_UP_TICKS {
+            self.next_deadline += self.timestep;
+            due += 1;
+        }
+        if due > 1 {
+            self.stats.late += due as u64 - 1;
+        }
+        if self.next_deadline <= now {
+            let behind = (now - self.next_deadline).as_nanos() / self.timestep.as_nanos() + 1;
+            eprintln!(
+                "Tick {} is {} ticks behind, skipping them",
+                self.tick, behind
+            );
+            self.next_deadline += self.timestep * behind as u32;
+            self.stats.skipped += behind as u64;
+        }
+        due
+    }
+
+    /// Records how long simulating one tick took.
+    pub fn record_tick_time(&mut self, elapsed: Duration) {
+        self.stats.ticks += 1;
+        self.stats.total += elapsed;
+        self.stats.max = self.stats.max.max(elapsed);
+        if elapsed > self.timestep {
+            self.stats.overruns += 1;
+        }
+    }
+
+    /// Prints tick times every `STATS_REPORT_INTERVAL` and starts counting afresh.
+    /// Returns whether it did, so other timings can be printed alongside.
+    pub fn report_if_due(&mut self) -> bool {
+        if self.last_report.elapsed() < STATS_REPORT_INTERVAL {
+            return false;
+        }
+        self.last_report = Instant::now();
+        let stats = std::mem::take(&mut self.stats);
+        println!(
+            "Tick {}: {} ticks, mean {:?}, max {:?}, {} overran, {} late, {} skipped",
+            self.tick,
+            stats.ticks,
+            stats.mean(),
+            stats.max,
+            stats.overruns,
+            stats.late,
+            stats.skipped
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_tick_is_due_after_each_wait() {
+        let mut scheduler = TickScheduler::new(60);
+        scheduler.start();
+        assert_eq!(scheduler.due_ticks(), 0);
+        for _ in 0..3 {
+            scheduler.wait().await;
+            assert!(scheduler.due_ticks() >= 1);
+        }
+    }
+
+    #[test]
+    fn a_late_server_catches_up_a_few_ticks_and_skips_the_rest() {
+        let mut scheduler = TickScheduler::new(60);
+        scheduler.next_deadline = Instant::now() - Duration::from_secs(1);
+        assert_eq!(scheduler.due_ticks(), MAX_CATCH_UP_TICKS);
+        assert!(scheduler.next_deadline > Instant::now());
+        assert_eq!(scheduler.stats.late, MAX_CATCH_UP_TICKS as u64 - 1);
+    }
+}
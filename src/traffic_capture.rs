@@ -0,0 +1,34 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use lazy_static::lazy_static;
+
+// set this to a directory to dump every received packet into it, one file each.
+// the dumps are what the fuzz seed corpus is built from
+pub const CAPTURE_DIR_ENV_VAR: &str = "EGGS_CAPTURE_DIR";
+
+lazy_static! {
+    static ref CAPTURE_DIR: Option<PathBuf> = env::var_os(CAPTURE_DIR_ENV_VAR).map(PathBuf::from);
+    static ref NEXT_CAPTURE_ID: AtomicU64 = AtomicU64::new(0);
+}
+
+pub fn capture_packet(direction: &str, packet: &[u8]) {
+    let capture_dir = match CAPTURE_DIR.as_ref() {
+        Some(capture_dir) => capture_dir,
+        None => return,
+    };
+
+    let capture_id = NEXT_CAPTURE_ID.fetch_add(1, Ordering::Relaxed);
+    let path = capture_dir.join(format!(
+        "{}-{}-{:06}.bin",
+        direction,
+        std::process::id(),
+        capture_id
+    ));
+    if let Err(e) = fs::create_dir_all(capture_dir).and_then(|_| fs::write(&path, packet)) {
+        eprintln!("Failed to capture packet to {:?}: {:?}", path, e);
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+
+use hecs::World;
+
+use crate::components::{InputQueue, OwnerClient};
+
+/// Why a message that tries to change game state was refused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    NothingToControl,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::NothingToControl => write!(f, "input without a player to control"),
+        }
+    }
+}
+
+/// Input is the only way a client moves anything, and it only ever moves the sender's
+/// own players, so it needs at least one. Decoding keeps each axis in -1..=1, and the
+/// server turns that into at most sprinting speed a tick and keeps it in the world, so
+/// that is all a client can do however it cheats.
+pub fn check_input(world: &World, client_id: u32) -> Result<(), Violation> {
+    let controls_something = world
+        .query::<&OwnerClient>()
+        .with::<&InputQueue>()
+        .iter()
+        .any(|(_, owner)| owner.client_id == client_id);
+    if !controls_something {
+        return Err(Violation::NothingToControl);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+
+    use super::*;
+    use crate::{
+        bitpack::Packed,
+        client_to_server::ClientToServerMessage,
+        codec::{decode, encode},
+        components::Registry,
+        game_objects::player_bundle,
+        input::{InputCommand, BUTTON_SPRINT},
+        settings::{PLAYER_SPEED, SPRINT_MULTIPLIER},
+    };
+
+    const OWNER: u32 = 1;
+    const SOMEONE_ELSE: u32 = 2;
+
+    #[test]
+    fn input_needs_a_controllable_player() {
+        let mut world = World::new();
+        let mut registry = Registry::new();
+        let entity_id = registry.spawn(&mut world, player_bundle(OWNER, Vec2::ZERO));
+        assert_eq!(check_input(&world, OWNER), Err(Violation::NothingToControl));
+
+        let entity = registry.entity(entity_id).unwrap();
+        world.insert_one(entity, InputQueue::default()).unwrap();
+        assert_eq!(check_input(&world, OWNER), Ok(()));
+        assert_eq!(
+            check_input(&world, SOMEONE_ELSE),
+            Err(Violation::NothingToControl)
+        );
+    }
+
+    #[test]
+    fn no_input_off_the_wire_moves_faster_than_sprinting() {
+        let max_speed = PLAYER_SPEED * SPRINT_MULTIPLIER;
+        let cheats = [
+            Vec2::ONE,
+            Vec2::splat(-1.0),
+            Vec2::new(50.0, -3.0),
+            Vec2::NAN,
+        ];
+        for movement in cheats {
+            let sent = InputCommand {
+                sequence: 1,
+                movement,
+                buttons: u8::MAX,
+            };
+            let bytes = encode(&ClientToServerMessage::Input {
+                command: Packed(sent),
+            })
+            .unwrap();
+            let Ok(ClientToServerMessage::Input {
+                command: Packed(received),
+            }) = decode(&bytes)
+            else {
+                panic!("input did not come back");
+            };
+            assert!(received.is_pressed(BUTTON_SPRINT));
+            assert!(received.movement.abs().cmple(Vec2::ONE).all());
+            assert!(received.velocity().length() <= max_speed + f32::EPSILON);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+/// Declares a message enum whose wire id for each variant is written out by hand,
+/// instead of being its position in the declaration like plain `#[derive(Serialize)]`.
+/// The bytes are the same as bincode's derive would produce for the same ids: the id
+/// as a u32, then the fields in order.
+///
+/// Ids are forever. See `docs/protocol.md` before adding, changing or removing one.
+macro_rules! wire_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $(
+                $id:literal => $variant:ident $({ $($field:ident : $ty:ty),* $(,)? })?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum $name {
+            $( $variant $({ $($field: $ty),* })? ),*
+        }
+
+        impl $name {
+            /// Every variant with its wire id, in declaration order.
+            pub const MESSAGE_IDS: &'static [(&'static str, u32)] =
+                &[$( (stringify!($variant), $id) ),*];
+
+            pub fn message_id(&self) -> u32 {
+                match self {
+                    $( $name::$variant { .. } => $id ),*
+                }
+            }
+
+            pub fn message_name(&self) -> &'static str {
+                match self {
+                    $( $name::$variant { .. } => stringify!($variant) ),*
+                }
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match self {
+                    $(
+                        $name::$variant $({ $($field),* })? => {
+                            wire_enum!(@serialize serializer, $name, $id, $variant $({ $($field),* })?)
+                        }
+                    )*
+                }
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct WireEnumVisitor;
+
+                impl<'de> serde::de::Visitor<'de> for WireEnumVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        write!(f, "a {} message id", stringify!($name))
+                    }
+
+                    fn visit_enum<A: serde::de::EnumAccess<'de>>(
+                        self,
+                        data: A,
+                    ) -> Result<Self::Value, A::Error> {
+                        let (id, variant): (u32, A::Variant) = data.variant()?;
+                        match id {
+                            $(
+                                $id => wire_enum!(@deserialize variant, $name, $variant $({ $($field : $ty),* })?),
+                            )*
+                            _ => Err(serde::de::Error::custom(format!(
+                                "unknown {} message id {}",
+                                stringify!($name),
+                                id
+                            ))),
+                        }
+                    }
+                }
+
+                const VARIANTS: &[&str] = &[$( stringify!($variant) ),*];
+                deserializer.deserialize_enum(stringify!($name), VARIANTS, WireEnumVisitor)
+            }
+        }
+    };
+
+    // unit variants are just the id
+    (@serialize $serializer:ident, $name:ident, $id:literal, $variant:ident) => {
+        $serializer.serialize_unit_variant(stringify!($name), $id, stringify!($variant))
+    };
+    // fields go out as a tuple, which bincode lays out exactly like a struct variant
+    (@serialize $serializer:ident, $name:ident, $id:literal, $variant:ident { $($field:ident),* }) => {
+        $serializer.serialize_newtype_variant(
+            stringify!($name),
+            $id,
+            stringify!($variant),
+            &( $($field,)* ),
+        )
+    };
+
+    (@deserialize $access:ident, $name:ident, $variant:ident) => {{
+        serde::de::VariantAccess::unit_variant($access)?;
+        Ok($name::$variant)
+    }};
+    (@deserialize $access:ident, $name:ident, $variant:ident { $($field:ident : $ty:ty),* }) => {{
+        let ( $($field,)* ): ( $($ty,)* ) = serde::de::VariantAccess::newtype_variant($access)?;
+        Ok($name::$variant { $($field),* })
+    }};
+}
+
+pub(crate) use wire_enum;
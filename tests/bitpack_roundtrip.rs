@@ -0,0 +1,378 @@
+//! Packed snapshots, positions and inputs must come back within the configured precision.
+//!
+//! Quantization only promises half a step of error inside the world bounds, and clamping
+//! outside them. See the packed fields section of `docs/protocol.md`.
+
+#[allow(dead_code)]
+#[path = "../src/bitpack.rs"]
+mod bitpack;
+#[allow(dead_code)]
+#[path = "../src/client_to_server.rs"]
+mod client_to_server;
+#[allow(dead_code)]
+#[path = "../src/codec.rs"]
+mod codec;
+#[allow(dead_code)]
+#[path = "../src/components.rs"]
+mod components;
+#[allow(dead_code)]
+#[path = "../src/game_objects.rs"]
+mod game_objects;
+#[allow(dead_code)]
+#[path = "../src/input.rs"]
+mod input;
+#[allow(dead_code)]
+#[path = "../src/replication.rs"]
+mod replication;
+#[allow(dead_code)]
+#[path = "../src/rpc.rs"]
+mod rpc;
+#[allow(dead_code)]
+#[path = "../src/server_to_client.rs"]
+mod server_to_client;
+#[allow(dead_code)]
+#[path = "../src/settings.rs"]
+mod settings;
+#[allow(dead_code)]
+#[path = "../src/snapshot.rs"]
+mod snapshot;
+#[allow(dead_code)]
+#[path = "../src/wire_enum.rs"]
+mod wire_enum;
+
+use bitpack::{BitPack, BitReader, BitWriter, Packed};
+use client_to_server::ClientToServerMessage;
+use components::{CTransform, OwnerClient, Physics, Shape};
+use glam::Vec2;
+use input::{InputCommand, BUTTON_SPRINT};
+use replication::EntityState;
+use server_to_client::ServerToClientMessage;
+use settings::{
+    INPUT_AXIS_PRECISION, POSITION_PRECISION, VELOCITY_LIMIT, VELOCITY_PRECISION, WORLD_MAX,
+    WORLD_MIN,
+};
+use snapshot::{
+    apply_update, diff, fit_update, EntityStates, WorldUpdate, POSITION_QUANTIZATION,
+    VELOCITY_QUANTIZATION,
+};
+
+fn round_trip<T: BitPack>(value: &T) -> T {
+    let mut writer = BitWriter::new();
+    value.pack(&mut writer);
+    let bytes = writer.finish();
+    let mut reader = BitReader::new(&bytes);
+    let unpacked = T::unpack(&mut reader).expect("packed value did not unpack");
+    assert!(reader.is_finished(), "bits left over after unpacking");
+    unpacked
+}
+
+fn assert_within(what: &str, sent: Vec2, received: Vec2, precision: f32) {
+    let error = (sent - received).abs();
+    // half a step, plus a little for float rounding in the test itself
+    let limit = precision / 2.0 + 1e-4;
+    assert!(
+        error.x <= limit && error.y <= limit,
+        "{} {:?} came back as {:?}, more than half of {} off",
+        what,
+        sent,
+        received,
+        precision
+    );
+}
+
+/// Points spread over `min..=max` that mostly fall between quantization steps.
+fn grid(min: Vec2, max: Vec2) -> Vec<Vec2> {
+    const STEPS: u32 = 97;
+    let mut points = Vec::new();
+    for i in 0..=STEPS {
+        for j in 0..=STEPS {
+            let t = Vec2::new(i as f32, j as f32) / STEPS as f32;
+            points.push(min + (max - min) * t);
+        }
+    }
+    points
+}
+
+#[test]
+fn positions_stay_within_precision() {
+    for pos in grid(WORLD_MIN, WORLD_MAX) {
+        let transform = round_trip(&CTransform { pos, rot: Vec2::X });
+        assert_within("position", pos, transform.pos, POSITION_PRECISION);
+    }
+}
+
+#[test]
+fn velocities_stay_within_precision() {
+    let limit = Vec2::splat(VELOCITY_LIMIT);
+    for vel in grid(-limit, limit) {
+        let physics = round_trip(&Physics { vel });
+        assert_within("velocity", vel, physics.vel, VELOCITY_PRECISION);
+    }
+}
+
+#[test]
+fn out_of_bounds_values_are_clamped() {
+    let transform = round_trip(&CTransform {
+        pos: Vec2::new(-50.0, 1000.0),
+        rot: Vec2::new(3.0, -3.0),
+    });
+    assert_eq!(transform.pos, Vec2::new(WORLD_MIN.x, WORLD_MAX.y));
+    assert_eq!(transform.rot, Vec2::new(1.0, -1.0));
+
+    let physics = round_trip(&Physics {
+        vel: Vec2::new(100.0, -100.0),
+    });
+    assert_eq!(physics.vel, Vec2::new(VELOCITY_LIMIT, -VELOCITY_LIMIT));
+
+    let transform = round_trip(&CTransform {
+        pos: Vec2::new(f32::NAN, f32::INFINITY),
+        rot: Vec2::ZERO,
+    });
+    assert!(transform.pos.is_finite());
+}
+
+#[test]
+fn quantization_fits_its_bits() {
+    assert_eq!(POSITION_QUANTIZATION.x.bits(), 13);
+    assert_eq!(POSITION_QUANTIZATION.y.bits(), 13);
+    assert_eq!(VELOCITY_QUANTIZATION.x.bits(), 11);
+}
+
+#[test]
+fn varints_round_trip() {
+    let values = [
+        0,
+        1,
+        15,
+        16,
+        255,
+        256,
+        4095,
+        65536,
+        1 << 28,
+        u32::MAX - 1,
+        u32::MAX,
+    ];
+    for value in values {
+        assert_eq!(round_trip(&value), value);
+    }
+}
+
+#[test]
+fn small_ids_take_few_bits() {
+    let sizes = [
+        (0, 5),
+        (15, 5),
+        (16, 10),
+        (255, 10),
+        (256, 15),
+        (u32::MAX, 40),
+    ];
+    for (value, bits) in sizes {
+        let mut writer = BitWriter::new();
+        writer.write_varint(value);
+        assert_eq!(writer.bits_used(), bits, "varint {}", value);
+    }
+}
+
+#[test]
+fn overlong_varint_is_rejected() {
+    // nine chunks, each saying another one follows
+    let mut writer = BitWriter::new();
+    for _ in 0..9 {
+        writer.write(0, 4);
+        writer.write_bool(true);
+    }
+    let bytes = writer.finish();
+    assert_eq!(BitReader::new(&bytes).read_varint(), None);
+}
+
+fn entity(pos: Vec2, vel: Vec2, owner: u32) -> EntityState {
+    EntityState::new()
+        .with(CTransform {
+            pos,
+            rot: Vec2::new(0.6, -0.8),
+        })
+        .with(Physics { vel })
+        .with(Shape {
+            dims: Vec2::new(12.0, 12.0),
+        })
+        .with(OwnerClient { client_id: owner })
+}
+
+fn send_through_codec(update: WorldUpdate) -> WorldUpdate {
+    let message = ServerToClientMessage::Replication {
+        update: Packed(update),
+    };
+    let bytes = codec::encode(&message).unwrap();
+    match codec::decode::<ServerToClientMessage>(&bytes).unwrap() {
+        ServerToClientMessage::Replication { update } => update.0,
+        other => panic!("decoded as {:?}", other),
+    }
+}
+
+fn assert_entities_within(sent: &EntityStates, received: &EntityStates) {
+    assert_eq!(sent.len(), received.len());
+    for (entity_id, sent) in sent {
+        let received = &received[entity_id];
+        let (sent_transform, received_transform) = (
+            sent.get::<CTransform>().unwrap(),
+            received.get::<CTransform>().unwrap(),
+        );
+        assert_within(
+            "position",
+            sent_transform.pos,
+            received_transform.pos,
+            POSITION_PRECISION,
+        );
+        assert_within(
+            "velocity",
+            sent.get::<Physics>().unwrap().vel,
+            received.get::<Physics>().unwrap().vel,
+            VELOCITY_PRECISION,
+        );
+        assert_eq!(sent.get::<Shape>(), received.get::<Shape>());
+        assert_eq!(sent.get::<OwnerClient>(), received.get::<OwnerClient>());
+    }
+}
+
+#[test]
+fn full_snapshot_round_trips_through_the_codec() {
+    let current: EntityStates = (0..20)
+        .map(|i| {
+            let pos = Vec2::new(i as f32 * 11.3, i as f32 * 7.9);
+            let vel = Vec2::new(i as f32 * 0.3 - 3.0, 1.1);
+            (i * 37, entity(pos, vel, i % 4))
+        })
+        .collect();
+
+    let update = send_through_codec(diff(123456, None, &current));
+    assert_eq!(update.tick, 123456);
+    assert_eq!(update.baseline_tick, None);
+    let received = apply_update(&EntityStates::new(), &update).unwrap();
+    assert_entities_within(&current, &received);
+}
+
+#[test]
+fn world_update_round_trips_through_the_codec() {
+    let mut baseline = EntityStates::new();
+    baseline.insert(7, entity(Vec2::new(10.0, 10.0), Vec2::ZERO, 1));
+    baseline.insert(8, entity(Vec2::new(20.0, 20.0), Vec2::ZERO, 2));
+    baseline.insert(70000, entity(Vec2::new(30.0, 30.0), Vec2::ZERO, 3));
+
+    let mut current = baseline.clone();
+    current.remove(&8);
+    current.insert(9, entity(Vec2::new(239.99, 0.01), Vec2::new(-0.7, 3.3), 12));
+    current.get_mut(&7).unwrap().insert(CTransform {
+        pos: Vec2::new(33.3, 101.7),
+        rot: Vec2::ZERO,
+    });
+
+    // wraps, the update only carries how far back the baseline is
+    let baseline_tick = u32::MAX - 2;
+    let update = send_through_codec(diff(5, Some((baseline_tick, &baseline)), &current));
+    assert_eq!(update.tick, 5);
+    assert_eq!(update.baseline_tick, Some(baseline_tick));
+    assert_eq!(update.despawned, vec![8]);
+    assert_eq!(update.spawned.len(), 1);
+    assert_eq!(update.updated.len(), 1);
+    // only the component that changed
+    assert_eq!(update.updated[0].changed.len(), 1);
+
+    let received = apply_update(&baseline, &update).unwrap();
+    assert_entities_within(&current, &received);
+}
+
+#[test]
+fn worlds_too_big_for_a_packet_arrive_over_several_updates() {
+    let world_at = |tick: u32| -> EntityStates {
+        (0..500)
+            .map(|i| {
+                let pos = Vec2::new(i as f32 * 0.47 + tick as f32, i as f32 * 0.31);
+                (i, entity(pos, Vec2::new(1.5, -2.5), i % 8))
+            })
+            .collect()
+    };
+
+    // each update is made against what the one before left the client with, the way
+    // the server does once the client acks it. everything moves all the while, so
+    // there is always more to update than fits
+    let mut sent: Option<(u32, EntityStates)> = None;
+    let mut updates = 0;
+    while sent.as_ref().map_or(0, |(_, entities)| entities.len()) < 500 {
+        assert!(updates < 20, "updates stopped bringing anything new");
+        let tick = updates + 1;
+        let baseline = sent.as_ref().map(|(tick, entities)| (*tick, entities));
+        let mut update = diff(tick, baseline, &world_at(tick));
+        fit_update(&mut update, codec::MAX_WORLD_UPDATE_BYTES);
+        // more entities than the retired player lists allowed
+        let arrived = send_through_codec(update.clone());
+        assert_eq!(arrived.spawned.len(), update.spawned.len());
+
+        let empty = EntityStates::new();
+        let baseline = baseline.map_or(&empty, |(_, entities)| entities);
+        sent = Some((tick, apply_update(baseline, &update).unwrap()));
+        updates += 1;
+    }
+    assert!(updates > 1);
+}
+
+#[test]
+fn input_commands_round_trip() {
+    let limit = Vec2::ONE;
+    for (i, movement) in grid(-limit, limit).into_iter().enumerate() {
+        let command = InputCommand {
+            sequence: u32::MAX - i as u32,
+            movement,
+            buttons: i as u8,
+        };
+        let received = round_trip(&command);
+        assert_eq!(received.sequence, command.sequence);
+        assert_eq!(received.buttons, command.buttons);
+        assert_within(
+            "movement",
+            movement,
+            received.movement,
+            INPUT_AXIS_PRECISION,
+        );
+    }
+
+    // keys are all or nothing, and those have to come through exactly
+    let keys = InputCommand {
+        sequence: 1,
+        movement: Vec2::new(-1.0, 1.0),
+        buttons: BUTTON_SPRINT,
+    };
+    assert_eq!(round_trip(&keys), keys);
+}
+
+#[test]
+fn truncated_and_trailing_data_is_rejected() {
+    let message = ClientToServerMessage::Input {
+        command: Packed(InputCommand {
+            sequence: 3,
+            movement: Vec2::new(1.0, -1.0),
+            buttons: BUTTON_SPRINT,
+        }),
+    };
+    let bytes = codec::encode(&message).unwrap();
+    assert!(codec::decode::<ClientToServerMessage>(&bytes).is_ok());
+
+    // message id, then the u16 byte count of the packed part
+    let packed_len = u16::from_le_bytes([bytes[4], bytes[5]]) as usize;
+    assert_eq!(bytes.len(), 6 + packed_len);
+
+    let mut short = bytes.clone();
+    short.pop();
+    short[4] -= 1;
+    assert!(codec::decode::<ClientToServerMessage>(&short).is_err());
+
+    let mut long = bytes.clone();
+    long.push(0xff);
+    long[4] += 1;
+    assert!(codec::decode::<ClientToServerMessage>(&long).is_err());
+
+    let mut cut = bytes;
+    cut.pop();
+    assert!(codec::decode::<ClientToServerMessage>(&cut).is_err());
+}
@@ -0,0 +1,261 @@
+//! Every packet the current protocol version sends must keep its exact layout, and no
+//! message id may ever change.
+//!
+//! `tests/golden/v<N>/<direction>/` holds one sample per message as of protocol version N,
+//! named after the variant (`<Variant>.bin`, or `<Variant>-<what>.bin` when one variant
+//! needs several). Only the current version is kept, since older peers are turned away
+//! at the handshake. See `docs/protocol.md`.
+
+#[allow(dead_code)]
+#[path = "../src/bitpack.rs"]
+mod bitpack;
+#[allow(dead_code)]
+#[path = "../src/client_to_server.rs"]
+mod client_to_server;
+#[allow(dead_code)]
+#[path = "../src/codec.rs"]
+mod codec;
+#[allow(dead_code)]
+#[path = "../src/components.rs"]
+mod components;
+#[allow(dead_code)]
+#[path = "../src/game_objects.rs"]
+mod game_objects;
+#[allow(dead_code)]
+#[path = "../src/input.rs"]
+mod input;
+#[allow(dead_code)]
+#[path = "../src/replication.rs"]
+mod replication;
+#[allow(dead_code)]
+#[path = "../src/rpc.rs"]
+mod rpc;
+#[allow(dead_code)]
+#[path = "../src/server_to_client.rs"]
+mod server_to_client;
+#[allow(dead_code)]
+#[path = "../src/settings.rs"]
+mod settings;
+#[allow(dead_code)]
+#[path = "../src/snapshot.rs"]
+mod snapshot;
+#[allow(dead_code)]
+#[path = "../src/wire_enum.rs"]
+mod wire_enum;
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use client_to_server::ClientToServerMessage;
+use codec::Validate;
+use serde::{de::DeserializeOwned, Serialize};
+use server_to_client::ServerToClientMessage;
+
+const CLIENT_TO_SERVER: &str = "client_to_server";
+const SERVER_TO_CLIENT: &str = "server_to_client";
+
+struct Sample {
+    path: PathBuf,
+    variant: String,
+    bytes: Vec<u8>,
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+}
+
+fn samples_in(dir: &Path) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return samples,
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|e| e.to_str()) != Some("bin") {
+            continue;
+        }
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        let variant = stem.split('-').next().unwrap().to_string();
+        let bytes = fs::read(&path).unwrap();
+        samples.push(Sample {
+            path,
+            variant,
+            bytes,
+        });
+    }
+    samples
+}
+
+fn current_dir(direction: &str) -> PathBuf {
+    golden_dir()
+        .join(format!("v{}", settings::PROTOCOL_VERSION))
+        .join(direction)
+}
+
+fn check_samples<T>(
+    direction: &str,
+    message_id: fn(&T) -> u32,
+    message_name: fn(&T) -> &'static str,
+) where
+    T: DeserializeOwned + Serialize + Validate,
+{
+    let samples = samples_in(&current_dir(direction));
+    assert!(!samples.is_empty(), "no {} samples", direction);
+    for sample in samples {
+        let message: T = codec::decode(&sample.bytes)
+            .unwrap_or_else(|e| panic!("{:?} no longer decodes: {}", sample.path, e));
+
+        let leading_id = u32::from_le_bytes(sample.bytes[..4].try_into().unwrap());
+        assert_eq!(leading_id, message_id(&message), "{:?}", sample.path);
+        assert_eq!(sample.variant, message_name(&message), "{:?}", sample.path);
+
+        // nothing about the layout may drift either, or peers on this version could
+        // not read us
+        assert_eq!(
+            codec::encode(&message).unwrap(),
+            sample.bytes,
+            "{:?} re-encodes differently",
+            sample.path
+        );
+    }
+}
+
+fn check_current_version_covered(direction: &str, message_ids: &[(&str, u32)]) {
+    let dir = current_dir(direction);
+    let have: HashSet<String> = samples_in(&dir).into_iter().map(|s| s.variant).collect();
+    for (name, _) in message_ids {
+        assert!(
+            have.contains(*name),
+            "{} has no golden sample in {:?}",
+            name,
+            dir
+        );
+    }
+}
+
+fn check_ids_unique(message_ids: &[(&str, u32)]) {
+    let mut seen = HashSet::new();
+    for (name, id) in message_ids {
+        assert!(seen.insert(*id), "{} reuses message id {}", name, id);
+    }
+}
+
+#[test]
+fn client_to_server_samples_decode() {
+    check_samples::<ClientToServerMessage>(
+        CLIENT_TO_SERVER,
+        ClientToServerMessage::message_id,
+        ClientToServerMessage::message_name,
+    );
+}
+
+#[test]
+fn server_to_client_samples_decode() {
+    check_samples::<ServerToClientMessage>(
+        SERVER_TO_CLIENT,
+        ServerToClientMessage::message_id,
+        ServerToClientMessage::message_name,
+    );
+}
+
+#[test]
+fn every_message_has_a_current_sample() {
+    check_current_version_covered(CLIENT_TO_SERVER, ClientToServerMessage::MESSAGE_IDS);
+    check_current_version_covered(SERVER_TO_CLIENT, ServerToClientMessage::MESSAGE_IDS);
+}
+
+#[test]
+fn message_ids_are_unique() {
+    check_ids_unique(ClientToServerMessage::MESSAGE_IDS);
+    check_ids_unique(ServerToClientMessage::MESSAGE_IDS);
+    check_ids_unique(server_to_client::RejectionReason::MESSAGE_IDS);
+    check_ids_unique(rpc::Request::MESSAGE_IDS);
+    check_ids_unique(rpc::Response::MESSAGE_IDS);
+    check_ids_unique(rpc::RpcError::MESSAGE_IDS);
+    check_ids_unique(replication::ComponentData::COMPONENT_IDS);
+}
+
+/// The id tables in `docs/protocol.md`. Ids only ever get added: the gaps are retired
+/// ones, which are never handed out again.
+#[test]
+fn message_ids_never_change() {
+    assert_eq!(
+        ClientToServerMessage::MESSAGE_IDS,
+        &[
+            ("Connect", 0),
+            ("Disconnect", 1),
+            ("ChatMessage", 2),
+            ("ConnectRequest", 6),
+            ("Ping", 7),
+            ("Pong", 8),
+            ("Request", 9),
+            ("SnapshotAck", 10),
+            ("Input", 12),
+        ]
+    );
+    assert_eq!(
+        ServerToClientMessage::MESSAGE_IDS,
+        &[
+            ("ClientIDAssignment", 0),
+            ("Welcome", 1),
+            ("ClientJoined", 2),
+            ("ClientLeft", 3),
+            ("ChatMessage", 4),
+            ("ConnectionRejected", 8),
+            ("ServerShutdown", 9),
+            ("Ping", 10),
+            ("Pong", 11),
+            ("Response", 12),
+            ("Replication", 18),
+        ]
+    );
+    assert_eq!(
+        server_to_client::RejectionReason::MESSAGE_IDS,
+        &[
+            ("ServerFull", 0),
+            ("Banned", 1),
+            ("VersionMismatch", 2),
+            ("ShuttingDown", 3),
+            ("Kicked", 4),
+        ]
+    );
+    assert_eq!(
+        rpc::Request::MESSAGE_IDS,
+        &[("AckSnapshot", 3), ("JoinV2", 4)]
+    );
+    assert_eq!(
+        rpc::Response::MESSAGE_IDS,
+        &[("AckSnapshot", 3), ("JoinV2", 4)]
+    );
+    assert_eq!(
+        rpc::RpcError::MESSAGE_IDS,
+        &[
+            ("NoHandler", 0),
+            ("AlreadySpawned", 1),
+            ("UnknownSnapshot", 2)
+        ]
+    );
+    assert_eq!(
+        replication::ComponentData::COMPONENT_IDS,
+        &[
+            ("CTransform", 0),
+            ("Physics", 1),
+            ("Shape", 2),
+            ("OwnerClient", 3),
+            ("ProcessedInput", 4),
+        ]
+    );
+}
+
+#[test]
+fn unknown_message_id_is_rejected() {
+    let unknown = u32::MAX.to_le_bytes();
+    assert!(codec::decode::<ClientToServerMessage>(&unknown).is_err());
+    assert!(codec::decode::<ServerToClientMessage>(&unknown).is_err());
+}